@@ -19,6 +19,10 @@ fn main() {
         pragma_once: true,
         cpp_compat: true,
         tab_width: 4,
+        enumeration: cbindgen::EnumConfig {
+            prefix_with_name: true,
+            ..Default::default()
+        },
         ..Default::default()
     };
 
@@ -113,7 +113,8 @@ out:
         {
             rv = status;
             assert(forward_graph_is_error_state(graph));
-            fprintf(stdout, "%s\n", forward_graph_get_error_message(graph, &status));
+            fprintf(stdout, "error code %d: %s\n", forward_graph_get_error_code(graph),
+                    forward_graph_get_error_message(graph, &status));
         }
     forward_graph_deallocate(graph);
     return rv;
@@ -1,29 +1,1143 @@
 use demes_forward::demes;
 use libc::c_char;
+use libc::c_void;
+use std::cell::RefCell;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::io::Read;
 
+/// Error codes reported by this library.
+///
+/// Functions returning an `i32` status, or writing one
+/// to a `status` pointer, use these values.
+/// Success is zero and all errors are negative.
+/// The numeric values are part of the stable API.
+///
+/// Only failures to initialize a graph, failures of
+/// [`forward_graph_update_state`] and panics put a graph into
+/// an error state.
+/// All other errors, such as those of [`Self::IndexOutOfRange`],
+/// are reported through the return value or `status` and the last
+/// error (see [`demes_forward_last_error_code`]) and leave the
+/// graph unchanged.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardGraphErrorCode {
+    /// No error.
+    NoError = 0,
+    /// A required pointer was NULL.
+    /// All functions check their pointer arguments
+    /// and report this code rather than dereferencing NULL.
+    NullPointer = -1,
+    /// Input was not valid utf8.
+    Utf8Error = -2,
+    /// An input file could not be opened or read.
+    IOError = -3,
+    /// The model could not be parsed.
+    YamlError = -4,
+    /// The model was parsed but is not a valid demes model.
+    DemesError = -5,
+    /// The model is valid but cannot be converted into a forward-time model.
+    ModelError = -6,
+    /// An invalid time value was given.
+    TimeError = -7,
+    /// A deme, epoch or pulse index is out of range.
+    /// This is reported through the return value or `status`
+    /// and the last error, and does not put the graph
+    /// into an error state, so later calls are unaffected.
+    IndexOutOfRange = -8,
+    /// A deme name is not present in the model.
+    UnknownDeme = -9,
+    /// [`forward_graph_initialize_time_iteration`] has not been called.
+    IterationNotInitialized = -10,
+    /// The graph has not been initialized from a model.
+    GraphNotInitialized = -11,
+    /// An unexpected error occurred.
+    InternalError = -12,
+    /// A `JSON` model could not be parsed.
+    JsonError = -13,
+    /// Invalid initialization options.
+    InvalidOptions = -14,
+    /// A panic occurred inside the library.
+    /// See [`forward_graph_catch_panics`] for when
+    /// panics can be caught.
+    Panic = -15,
+    /// A graph handle has been freed or was not
+    /// returned by [`forward_graph_allocate`].
+    /// Only detected with the `checked-handles` feature.
+    InvalidHandle = -16,
+    /// A value does not fit in the requested integer type.
+    IntegerOverflow = -17,
+    /// An argument is outside of its valid range.
+    InvalidArgument = -18,
+    /// An offspring deme has no ancestry because
+    /// it does not exist in the current generation.
+    NoAncestry = -19,
+}
+
+impl ForwardGraphErrorCode {
+    // Used for the last error when there is
+    // no more specific message.
+    fn message(&self) -> &'static str {
+        match self {
+            Self::NoError => "no error",
+            Self::NullPointer => "a required pointer is NULL",
+            Self::Utf8Error => "input is not valid utf8",
+            Self::IOError => "input could not be read",
+            Self::YamlError => "the model could not be parsed",
+            Self::DemesError => "the model is not a valid demes model",
+            Self::ModelError => "the model cannot be converted into a forward-time model",
+            Self::TimeError => "invalid time value",
+            Self::IndexOutOfRange => "index out of range",
+            Self::UnknownDeme => "unknown deme name",
+            Self::IterationNotInitialized => {
+                "forward_graph_initialize_time_iteration has not been called"
+            }
+            Self::GraphNotInitialized => "the graph has not been initialized",
+            Self::InternalError => "internal error",
+            Self::JsonError => "the JSON model could not be parsed",
+            Self::InvalidOptions => "invalid initialization options",
+            Self::Panic => "panic",
+            Self::InvalidHandle => "invalid graph handle",
+            Self::IntegerOverflow => "value does not fit in the integer type",
+            Self::InvalidArgument => "invalid argument",
+            Self::NoAncestry => "offspring deme has no ancestry",
+        }
+    }
+}
+
+impl From<&demes::DemesError> for ForwardGraphErrorCode {
+    fn from(error: &demes::DemesError) -> Self {
+        match error {
+            demes::DemesError::YamlError(_) => Self::YamlError,
+            _ => Self::DemesError,
+        }
+    }
+}
+
+impl From<&demes_forward::DemesForwardError> for ForwardGraphErrorCode {
+    fn from(error: &demes_forward::DemesForwardError) -> Self {
+        match error {
+            demes_forward::DemesForwardError::DemesError(e) => e.into(),
+            demes_forward::DemesForwardError::TimeError(_) => Self::TimeError,
+            demes_forward::DemesForwardError::InvalidDemeSize(_) => Self::ModelError,
+            demes_forward::DemesForwardError::InternalError(_) => Self::InternalError,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<(ForwardGraphErrorCode, CString)>> = const { RefCell::new(None) };
+}
+
+// Interior NUL bytes are the only thing
+// that cannot be represented in a C string.
+fn error_message_cstring(message: &str) -> CString {
+    CString::new(message.replace('\0', "")).unwrap_or_default()
+}
+
+fn set_last_error(code: ForwardGraphErrorCode, message: CString) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some((code, message)));
+}
+
+// Record an error that has no more
+// specific message and return its code.
+fn record_error(code: ForwardGraphErrorCode) -> i32 {
+    set_last_error(code, error_message_cstring(code.message()));
+    code as i32
+}
+
+unsafe fn set_status(status: *mut i32, code: ForwardGraphErrorCode) {
+    *status = code as i32;
+    if code != ForwardGraphErrorCode::NoError {
+        record_error(code);
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic payload".to_string(),
+        },
+    };
+    format!("panic: {}", message)
+}
+
+// Run the body of an exported function that has no graph,
+// returning `on_panic` if it panics.
+fn catch_panic<R, F: FnOnce() -> R>(on_panic: R, f: F) -> R {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(rv) => rv,
+        Err(payload) => {
+            set_last_error(
+                ForwardGraphErrorCode::Panic,
+                error_message_cstring(&panic_message(payload)),
+            );
+            on_panic
+        }
+    }
+}
+
+// Run the body of an exported function, returning `on_panic` if it panics.
+// The panic is recorded as an error on `graph` and as the last error,
+// and is written to `status`.
+// The graph is reset because it may have been left partially updated.
+unsafe fn ffi_boundary<R, F: FnOnce() -> R>(
+    graph: *const OpaqueForwardGraph,
+    status: *mut i32,
+    on_panic: R,
+    f: F,
+) -> R {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(rv) => rv,
+        Err(payload) => {
+            let error = (ForwardGraphErrorCode::Panic, panic_message(payload));
+            if !status.is_null() {
+                *status = ForwardGraphErrorCode::Panic as i32;
+            }
+            if graph.is_null() {
+                set_last_error(error.0, error_message_cstring(&error.1));
+            } else {
+                // Handles are always allocated as mutable
+                // by forward_graph_allocate.
+                (*(graph as *mut OpaqueForwardGraph)).update(None, Some(error));
+            }
+            on_panic
+        }
+    }
+}
+
+unsafe fn null_pointer_error(name: &str, status: *mut i32) {
+    if !status.is_null() {
+        *status = ForwardGraphErrorCode::NullPointer as i32;
+    }
+    set_last_error(
+        ForwardGraphErrorCode::NullPointer,
+        error_message_cstring(&format!("{} is NULL", name)),
+    );
+}
+
+// With the `checked-handles` feature, each handle carries a tag
+// that is checked by every function.
+// Freed handles are kept with a different tag
+// rather than being returned to the allocator.
+#[cfg(feature = "checked-handles")]
+const LIVE_HANDLE: u64 = 0x4445_4d45_5346_5744;
+#[cfg(feature = "checked-handles")]
+const FREED_HANDLE: u64 = 0x4652_4545_4446_5744;
+
+#[cfg(feature = "checked-handles")]
+static NEXT_HANDLE_GENERATION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+#[cfg(feature = "checked-handles")]
+#[derive(Copy, Clone, Debug)]
+struct HandleTag {
+    magic: u64,
+    // Identifies the allocation in error messages.
+    generation: u64,
+}
+
+#[cfg(feature = "checked-handles")]
+impl HandleTag {
+    fn new() -> Self {
+        Self {
+            magic: LIVE_HANDLE,
+            generation: NEXT_HANDLE_GENERATION.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+// Check that a non-NULL `graph` is a live handle,
+// recording an error if it is not.
+// Always succeeds without the `checked-handles` feature.
+unsafe fn invalid_handle(graph: *const OpaqueForwardGraph, status: *mut i32) -> bool {
+    #[cfg(feature = "checked-handles")]
+    {
+        let tag = std::ptr::addr_of!((*graph).tag).read();
+        let message = match tag.magic {
+            LIVE_HANDLE => return false,
+            FREED_HANDLE => format!("graph handle {} has been freed", tag.generation),
+            _ => "graph is not a handle returned by forward_graph_allocate".to_string(),
+        };
+        if !status.is_null() {
+            *status = ForwardGraphErrorCode::InvalidHandle as i32;
+        }
+        set_last_error(
+            ForwardGraphErrorCode::InvalidHandle,
+            error_message_cstring(&message),
+        );
+        true
+    }
+    #[cfg(not(feature = "checked-handles"))]
+    {
+        let _ = (graph, status);
+        false
+    }
+}
+
+// Return `$rv` from the calling function if `$graph`
+// is not a live handle.
+macro_rules! return_if_invalid_handle {
+    ($graph:ident, $rv:expr) => {
+        if invalid_handle($graph, std::ptr::null_mut()) {
+            return $rv;
+        }
+    };
+    ($graph:ident, $status:ident, $rv:expr) => {
+        if invalid_handle($graph, $status) {
+            return $rv;
+        }
+    };
+}
+
+// Return `$rv` from the calling function if `$ptr` is NULL.
+// The error is recorded as the last error and
+// written to `$status` when one is given.
+macro_rules! return_if_null {
+    ($ptr:ident, $rv:expr) => {
+        if $ptr.is_null() {
+            null_pointer_error(stringify!($ptr), std::ptr::null_mut());
+            return $rv;
+        }
+    };
+    ($ptr:ident, $status:ident, $rv:expr) => {
+        if $ptr.is_null() {
+            null_pointer_error(stringify!($ptr), $status);
+            return $rv;
+        }
+    };
+}
+
+// Implement TryFrom<i32> for a C enum, which gives back
+// the value if it is not one of the listed variants.
+// Enums are received from C as integers because values
+// outside of the declared variants are undefined behaviour
+// for a rust enum.
+macro_rules! impl_try_from_i32 {
+    ($enum:ident, $($variant:ident),+) => {
+        impl TryFrom<i32> for $enum {
+            type Error = i32;
+
+            fn try_from(value: i32) -> Result<Self, i32> {
+                $(
+                    if value == Self::$variant as i32 {
+                        return Ok(Self::$variant);
+                    }
+                )+
+                Err(value)
+            }
+        }
+    };
+}
+
+/// How times are converted to integer generations
+/// when a graph is initialized.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardGraphTimeRounding {
+    /// Round to the nearest integer.
+    /// This is the default.
+    Round = 0,
+    /// Do not round.
+    /// Initialization fails if any time
+    /// is not an integer number of generations.
+    NoRounding = 1,
+}
+
+/// How the burn-in time of [`ForwardGraphInitializationOptions`]
+/// is interpreted.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardGraphBurninUnits {
+    /// The burn-in is a number of generations.
+    /// This is the default.
+    Generations = 0,
+    /// The burn-in is a multiple of the size of the root deme
+    /// at the start of the model.
+    /// If there is more than one root deme, the largest is used.
+    RootDemeSize = 1,
+    /// The burn-in is a multiple of the largest
+    /// deme size at time zero.
+    MaxDemeSizeAtTimeZero = 2,
+}
+
+impl_try_from_i32!(ForwardGraphTimeRounding, Round, NoRounding);
+impl_try_from_i32!(
+    ForwardGraphBurninUnits,
+    Generations,
+    RootDemeSize,
+    MaxDemeSizeAtTimeZero
+);
+
+// Read an enum field of the options as an integer
+// so that invalid values can be rejected.
+unsafe fn read_options_enum<T: TryFrom<i32, Error = i32>>(
+    field: *const T,
+    name: &str,
+) -> Result<T, (ForwardGraphErrorCode, String)> {
+    T::try_from((field as *const i32).read()).map_err(|value| {
+        (
+            ForwardGraphErrorCode::InvalidOptions,
+            format!("invalid value {} for {}", value, name),
+        )
+    })
+}
+
+/// The current version of [`ForwardGraphInitializationOptions`].
+pub const FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION: u32 = 1;
+
+/// Options for initializing a graph.
+///
+/// The layout of this struct is versioned.
+/// New fields are only ever appended and each
+/// addition increments [`FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION`].
+/// Instances must be set up by
+/// [`forward_graph_initialization_options_init`],
+/// which records the version and size that the caller was compiled against.
+/// Fields may then be modified before use.
+///
+/// There is no separate strictness setting:
+/// [`ForwardGraphTimeRounding::NoRounding`] and `require_generations`
+/// make initialization fail rather than convert model times.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ForwardGraphInitializationOptions {
+    /// The layout version of this struct.
+    pub version: u32,
+    /// The size of this struct in bytes, for the layout `version`.
+    pub size: usize,
+    /// The burn-in time, interpreted according to `burnin_units`.
+    /// The default is `0`.
+    pub burnin: f64,
+    /// How model times are converted to generations.
+    /// The default is [`ForwardGraphTimeRounding::Round`].
+    pub rounding: ForwardGraphTimeRounding,
+    /// If `true`, initialization fails unless
+    /// the model time units are generations.
+    /// The default is `false`.
+    pub require_generations: bool,
+    /// How `burnin` is interpreted.
+    /// When it is a multiple of a deme size, the
+    /// resulting number of generations is rounded
+    /// to the nearest integer.
+    /// The default is [`ForwardGraphBurninUnits::Generations`].
+    pub burnin_units: ForwardGraphBurninUnits,
+}
+
+impl Default for ForwardGraphInitializationOptions {
+    fn default() -> Self {
+        Self {
+            version: FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION,
+            size: std::mem::size_of::<Self>(),
+            burnin: 0.0,
+            rounding: ForwardGraphTimeRounding::Round,
+            require_generations: false,
+            burnin_units: ForwardGraphBurninUnits::Generations,
+        }
+    }
+}
+
+impl ForwardGraphInitializationOptions {
+    // Options used by the initialization
+    // functions that only take a burn-in time.
+    fn with_burnin(burnin: f64) -> Self {
+        Self {
+            burnin,
+            ..Self::default()
+        }
+    }
+}
+
+// The size of the options struct in each supported layout version.
+fn initialization_options_size(version: u32) -> Option<usize> {
+    match version {
+        1 => Some(std::mem::size_of::<ForwardGraphInitializationOptions>()),
+        _ => None,
+    }
+}
+
+// Only read the fields that exist in
+// the version that the caller was compiled against.
+unsafe fn read_initialization_options(
+    options: *const ForwardGraphInitializationOptions,
+) -> Result<ForwardGraphInitializationOptions, (ForwardGraphErrorCode, String)> {
+    if options.is_null() {
+        return Err((
+            ForwardGraphErrorCode::NullPointer,
+            "initialization options are NULL".to_string(),
+        ));
+    }
+    let version = std::ptr::addr_of!((*options).version).read();
+    let expected_size = initialization_options_size(version).ok_or_else(|| {
+        (
+            ForwardGraphErrorCode::InvalidOptions,
+            format!("unsupported initialization options version {}", version),
+        )
+    })?;
+    let size = std::ptr::addr_of!((*options).size).read();
+    if size < expected_size {
+        return Err((
+            ForwardGraphErrorCode::InvalidOptions,
+            format!(
+                "initialization options size {} is smaller than {} for version {}",
+                size, expected_size, version
+            ),
+        ));
+    }
+    Ok(ForwardGraphInitializationOptions {
+        version: FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION,
+        size: std::mem::size_of::<ForwardGraphInitializationOptions>(),
+        burnin: std::ptr::addr_of!((*options).burnin).read(),
+        rounding: read_options_enum(std::ptr::addr_of!((*options).rounding), "rounding")?,
+        require_generations: match (std::ptr::addr_of!((*options).require_generations) as *const u8)
+            .read()
+        {
+            0 => false,
+            1 => true,
+            value => {
+                return Err((
+                    ForwardGraphErrorCode::InvalidOptions,
+                    format!("invalid value {} for require_generations", value),
+                ))
+            }
+        },
+        burnin_units: read_options_enum(
+            std::ptr::addr_of!((*options).burnin_units),
+            "burnin_units",
+        )?,
+    })
+}
+
+/// The size function of an epoch.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardGraphSizeFunction {
+    /// The size is constant.
+    Constant = 0,
+    /// The size changes linearly from the start
+    /// size to the end size.
+    Linear = 1,
+    /// The size changes exponentially from the
+    /// start size to the end size.
+    Exponential = 2,
+}
+
+impl From<demes::SizeFunction> for ForwardGraphSizeFunction {
+    fn from(size_function: demes::SizeFunction) -> Self {
+        match size_function {
+            demes::SizeFunction::Constant => Self::Constant,
+            demes::SizeFunction::Linear => Self::Linear,
+            demes::SizeFunction::Exponential => Self::Exponential,
+        }
+    }
+}
+
+/// An epoch of a deme.
+///
+/// Times are in generations, backwards in time
+/// from the end of the model, after conversion
+/// and rounding of the input model.
+/// The start time of the first epoch of a root
+/// deme is infinite.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ForwardGraphEpoch {
+    /// The start time of the epoch,
+    /// which is the end time of the previous epoch.
+    pub start_time: f64,
+    /// The end time of the epoch.
+    pub end_time: f64,
+    /// The deme size at the start of the epoch.
+    pub start_size: f64,
+    /// The deme size at the end of the epoch.
+    pub end_size: f64,
+    /// How the size changes during the epoch.
+    pub size_function: ForwardGraphSizeFunction,
+    /// The selfing rate during the epoch.
+    pub selfing_rate: f64,
+    /// The cloning rate during the epoch.
+    pub cloning_rate: f64,
+}
+
+fn deme_epochs(deme: &demes::Deme) -> Vec<ForwardGraphEpoch> {
+    let mut start_time = f64::from(deme.start_time());
+    deme.epochs()
+        .iter()
+        .map(|epoch| {
+            let rv = ForwardGraphEpoch {
+                start_time,
+                end_time: f64::from(epoch.end_time()),
+                start_size: f64::from(epoch.start_size()),
+                end_size: f64::from(epoch.end_size()),
+                size_function: epoch.size_function().into(),
+                selfing_rate: f64::from(epoch.selfing_rate()),
+                cloning_rate: f64::from(epoch.cloning_rate()),
+            };
+            start_time = rv.end_time;
+            rv
+        })
+        .collect()
+}
+
+// The burn-in in generations.
+fn burnin_generations(
+    graph: &demes::Graph,
+    options: &ForwardGraphInitializationOptions,
+) -> Result<f64, (ForwardGraphErrorCode, String)> {
+    let size = match options.burnin_units {
+        ForwardGraphBurninUnits::Generations => return Ok(options.burnin),
+        ForwardGraphBurninUnits::RootDemeSize => graph
+            .demes()
+            .iter()
+            .filter(|deme| deme.num_ancestors() == 0)
+            .map(|deme| f64::from(deme.start_size()))
+            .fold(None, |max: Option<f64>, size| {
+                Some(max.map_or(size, |max| max.max(size)))
+            }),
+        ForwardGraphBurninUnits::MaxDemeSizeAtTimeZero => graph
+            .demes()
+            .iter()
+            .filter(|deme| f64::from(deme.end_time()) == 0.0)
+            .filter_map(|deme| deme.end_sizes().last().map(|&size| f64::from(size)))
+            .fold(None, |max: Option<f64>, size| {
+                Some(max.map_or(size, |max| max.max(size)))
+            }),
+    };
+    match size {
+        Some(size) => Ok((options.burnin * size).round()),
+        None => Err((
+            ForwardGraphErrorCode::ModelError,
+            "no deme size to scale the burn-in by".to_string(),
+        )),
+    }
+}
+
+fn deme_index(graph: &demes::Graph, name: &str) -> usize {
+    graph
+        .demes()
+        .iter()
+        .position(|deme| deme.name().as_str() == name)
+        .unwrap()
+}
+
+// A continuous migration epoch with
+// deme names resolved to indexes.
+struct Migration {
+    source: usize,
+    dest: usize,
+    rate: f64,
+    start_time: f64,
+    end_time: f64,
+}
+
+fn model_migrations(graph: &demes::Graph) -> Vec<Migration> {
+    graph
+        .migrations()
+        .iter()
+        .map(|migration| Migration {
+            source: deme_index(graph, migration.source()),
+            dest: deme_index(graph, migration.dest()),
+            rate: f64::from(migration.rate()),
+            start_time: f64::from(migration.start_time()),
+            end_time: f64::from(migration.end_time()),
+        })
+        .collect()
+}
+
+// A pulse with deme names resolved to indexes.
+struct Pulse {
+    time: f64,
+    sources: Vec<usize>,
+    dest: usize,
+    proportions: Vec<f64>,
+}
+
+fn model_pulses(graph: &demes::Graph) -> Vec<Pulse> {
+    graph
+        .pulses()
+        .iter()
+        .map(|pulse| Pulse {
+            time: f64::from(pulse.time()),
+            sources: pulse
+                .sources()
+                .iter()
+                .map(|source| deme_index(graph, source))
+                .collect(),
+            dest: deme_index(graph, pulse.dest()),
+            proportions: pulse
+                .proportions()
+                .iter()
+                .map(|proportion| f64::from(*proportion))
+                .collect(),
+        })
+        .collect()
+}
+
+/// A pulse event.
+///
+/// The `sources` and `proportions` arrays have length
+/// `num_sources` and are owned by the graph.
+/// They remain valid until the graph is re-initialized
+/// or deallocated.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ForwardGraphPulse {
+    /// The (forward) time of the parental generation
+    /// whose offspring are affected by the pulse.
+    pub time: f64,
+    /// The index of the destination deme.
+    pub dest: usize,
+    /// The number of source demes.
+    pub num_sources: usize,
+    /// The indexes of the source demes.
+    pub sources: *const usize,
+    /// The proportion of ancestry of the destination
+    /// deme that comes from each source deme.
+    pub proportions: *const f64,
+}
+
+#[derive(Copy, Clone)]
+enum ModelFormat {
+    Yaml,
+    Json,
+}
+
+// The input used to initialize a graph,
+// kept so that the graph can be cloned.
+// The text is shared between a graph and its clones.
+#[derive(Clone)]
+struct ModelSource {
+    text: std::sync::Arc<str>,
+    format: ModelFormat,
+    options: ForwardGraphInitializationOptions,
+}
+
+fn load_model(
+    model: &str,
+    format: ModelFormat,
+) -> Result<demes::Graph, (ForwardGraphErrorCode, String)> {
+    match format {
+        ModelFormat::Yaml => {
+            demes::loads(model).map_err(|e| (ForwardGraphErrorCode::from(&e), format!("{}", e)))
+        }
+        // demes only reads YAML.
+        // Compact JSON is valid YAML, but JSON in general is not
+        // because YAML parsers reject some JSON escapes and tabs,
+        // so the model is parsed here and written back out compactly.
+        // Positions in the messages of parse errors
+        // reported by demes refer to the compact form.
+        ModelFormat::Json => {
+            let value = serde_json::from_str::<serde_json::Value>(model)
+                .map_err(|e| (ForwardGraphErrorCode::JsonError, format!("{}", e)))?;
+            demes::loads(&value.to_string()).map_err(|e| {
+                let code = match e {
+                    demes::DemesError::YamlError(_) => ForwardGraphErrorCode::JsonError,
+                    _ => ForwardGraphErrorCode::from(&e),
+                };
+                (code, format!("{}", e))
+            })
+        }
+    }
+}
+
+// Where the text of a model is read from.
+#[derive(Copy, Clone)]
+enum ModelInput {
+    // A NULL-terminated string.
+    String(*const c_char),
+    // A buffer of the given length that need not be NULL-terminated.
+    Buffer(*const u8, usize),
+    // A file whose name is a NULL-terminated string.
+    File(*const c_char),
+}
+
+// How the options of an initialization function are given.
+#[derive(Copy, Clone)]
+enum InitializationOptions {
+    // Only a burn-in time, in generations.
+    Burnin(f64),
+    // A struct set up by forward_graph_initialization_options_init.
+    Struct(*const ForwardGraphInitializationOptions),
+}
+
+impl InitializationOptions {
+    unsafe fn read(
+        self,
+    ) -> Result<ForwardGraphInitializationOptions, (ForwardGraphErrorCode, String)> {
+        match self {
+            Self::Burnin(burnin) => Ok(ForwardGraphInitializationOptions::with_burnin(burnin)),
+            Self::Struct(options) => read_initialization_options(options),
+        }
+    }
+}
+
+// Errors carry the byte offset of an invalid utf8 sequence in a buffer.
+unsafe fn read_model_input(
+    input: ModelInput,
+) -> Result<std::sync::Arc<str>, (ForwardGraphErrorCode, String, Option<usize>)> {
+    match input {
+        ModelInput::String(model) => {
+            if model.is_null() {
+                return Err((
+                    ForwardGraphErrorCode::NullPointer,
+                    "could not convert c_char to String".to_string(),
+                    None,
+                ));
+            }
+            CStr::from_ptr(model)
+                .to_str()
+                .map(Into::into)
+                .map_err(|e| (ForwardGraphErrorCode::Utf8Error, format!("{}", e), None))
+        }
+        ModelInput::Buffer(data, len) => {
+            if data.is_null() && len > 0 {
+                return Err((
+                    ForwardGraphErrorCode::NullPointer,
+                    "buffer is NULL".to_string(),
+                    None,
+                ));
+            }
+            let data = if len > 0 {
+                std::slice::from_raw_parts(data, len)
+            } else {
+                &[]
+            };
+            std::str::from_utf8(data).map(Into::into).map_err(|e| {
+                (
+                    ForwardGraphErrorCode::Utf8Error,
+                    format!(
+                        "invalid utf8 sequence at byte offset {}: {}",
+                        e.valid_up_to(),
+                        e
+                    ),
+                    Some(e.valid_up_to()),
+                )
+            })
+        }
+        ModelInput::File(file_name) => {
+            if file_name.is_null() {
+                return Err((
+                    ForwardGraphErrorCode::NullPointer,
+                    "file name is NULL".to_string(),
+                    None,
+                ));
+            }
+            let filename = CStr::from_ptr(file_name)
+                .to_str()
+                .map_err(|e| (ForwardGraphErrorCode::Utf8Error, format!("{}", e), None))?;
+            let mut buf = String::default();
+            std::fs::File::open(filename)
+                .and_then(|mut file| file.read_to_string(&mut buf))
+                .map_err(|e| (ForwardGraphErrorCode::IOError, format!("{}", e), None))?;
+            Ok(buf.into())
+        }
+    }
+}
+
+// The body shared by every initialization function.
+unsafe fn initialize_graph(
+    graph: *mut OpaqueForwardGraph,
+    input: ModelInput,
+    format: ModelFormat,
+    options: InitializationOptions,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            let source = options
+                .read()
+                .map_err(|(code, message)| (code, message, None))
+                .and_then(|options| {
+                    Ok(ModelSource {
+                        text: read_model_input(input)?,
+                        format,
+                        options,
+                    })
+                });
+            match source {
+                Ok(source) => (*graph).initialize_from_source(source),
+                Err((code, message, offset)) => {
+                    (*graph).update(None, Some((code, message)));
+                    (*graph).error_offset = offset;
+                    code as i32
+                }
+            }
+        },
+    )
+}
+
 pub struct OpaqueForwardGraph {
+    #[cfg(feature = "checked-handles")]
+    tag: HandleTag,
     graph: Option<demes_forward::ForwardGraph>,
+    model: Option<ModelSource>,
     error: Option<CString>,
+    error_code: ForwardGraphErrorCode,
+    // The byte offset of an invalid utf8 sequence
+    // in a buffer that caused the current error.
+    error_offset: Option<usize>,
     current_time: Option<f64>,
+    deme_names: Vec<CString>,
+    epochs: Vec<Vec<ForwardGraphEpoch>>,
+    migrations: Vec<Migration>,
+    migration_matrix: Vec<f64>,
+    pulses: Vec<Pulse>,
+    current_pulses: Vec<usize>,
+    ancestry_proportions: Vec<f64>,
 }
 
 impl OpaqueForwardGraph {
-    fn update(&mut self, graph: Option<demes_forward::ForwardGraph>, error: Option<String>) {
+    fn new() -> Self {
+        Self {
+            #[cfg(feature = "checked-handles")]
+            tag: HandleTag::new(),
+            graph: None,
+            model: None,
+            error: None,
+            error_code: ForwardGraphErrorCode::NoError,
+            error_offset: None,
+            current_time: None,
+            deme_names: vec![],
+            epochs: vec![],
+            migrations: vec![],
+            migration_matrix: vec![],
+            pulses: vec![],
+            current_pulses: vec![],
+            ancestry_proportions: vec![],
+        }
+    }
+
+    fn update(
+        &mut self,
+        graph: Option<demes_forward::ForwardGraph>,
+        error: Option<(ForwardGraphErrorCode, String)>,
+    ) {
+        if graph.is_none() {
+            self.model = None;
+            self.deme_names.clear();
+            self.epochs.clear();
+            self.migrations.clear();
+            self.migration_matrix.clear();
+            self.pulses.clear();
+            self.current_pulses.clear();
+            self.ancestry_proportions.clear();
+        }
         self.graph = graph;
         self.update_error(error);
     }
 
-    fn update_error(&mut self, error: Option<String>) {
-        self.error = error.map(|e| {
-            CString::new(
-                e.chars()
-                    .filter(|c| c.is_ascii() && c != &'"')
-                    .collect::<String>(),
-            )
-            .unwrap()
+    // The model is parsed again from its source rather
+    // than copied because demes::Graph and ForwardGraph
+    // do not implement Clone and there is no public way
+    // to build either one without validation.
+    fn try_clone(&self) -> Result<Self, (ForwardGraphErrorCode, String)> {
+        let mut rv = Self::new();
+        if let Some(source) = &self.model {
+            let code = rv.initialize_from_source(source.clone());
+            if code != ForwardGraphErrorCode::NoError as i32 {
+                return Err((
+                    rv.error_code,
+                    format!(
+                        "failed to rebuild the model: {}",
+                        rv.error
+                            .as_ref()
+                            .map(|e| e.to_string_lossy().into_owned())
+                            .unwrap_or_default()
+                    ),
+                ));
+            }
+            if let Some(time) = self
+                .graph
+                .as_ref()
+                .and_then(|fgraph| fgraph.last_time_updated())
+            {
+                rv.update_state(time.value())
+                    .map_err(|e| (ForwardGraphErrorCode::from(&e), format!("{}", e)))?;
+            }
+        }
+        rv.current_time = self.current_time;
+        rv.error_code = self.error_code;
+        rv.error = self.error.clone();
+        rv.error_offset = self.error_offset;
+        Ok(rv)
+    }
+
+    fn initialize_from_source(&mut self, source: ModelSource) -> i32 {
+        match load_model(&source.text, source.format) {
+            Ok(dg) => {
+                let rv = self.initialize(dg, &source.options);
+                if rv == ForwardGraphErrorCode::NoError as i32 {
+                    self.model = Some(source);
+                }
+                rv
+            }
+            Err((code, message)) => {
+                self.update(None, Some((code, message)));
+                code as i32
+            }
+        }
+    }
+
+    fn initialize(&mut self, dg: demes::Graph, options: &ForwardGraphInitializationOptions) -> i32 {
+        if options.require_generations && !matches!(dg.time_units(), demes::TimeUnits::Generations)
+        {
+            self.update(
+                None,
+                Some((
+                    ForwardGraphErrorCode::ModelError,
+                    format!(
+                        "time units must be generations, but are {}",
+                        dg.time_units()
+                    ),
+                )),
+            );
+            return ForwardGraphErrorCode::ModelError as i32;
+        }
+        let rounding = match options.rounding {
+            ForwardGraphTimeRounding::Round => Some(demes::RoundTimeToInteger::F64),
+            ForwardGraphTimeRounding::NoRounding => None,
+        };
+        let dg = match rounding {
+            Some(r) => dg.to_integer_generations(r),
+            None => dg.to_generations(),
+        };
+        let dg = match dg {
+            Ok(graph) => graph,
+            Err(e) => {
+                self.update(
+                    None,
+                    Some((ForwardGraphErrorCode::ModelError, format!("{}", e))),
+                );
+                return ForwardGraphErrorCode::ModelError as i32;
+            }
+        };
+        let deme_names = match dg
+            .demes()
+            .iter()
+            .map(|deme| CString::new(deme.name().as_str()))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(names) => names,
+            Err(e) => {
+                self.update(
+                    None,
+                    Some((ForwardGraphErrorCode::InternalError, format!("{}", e))),
+                );
+                return ForwardGraphErrorCode::InternalError as i32;
+            }
+        };
+        let burnin = match burnin_generations(&dg, options) {
+            Ok(burnin) => burnin,
+            Err((code, message)) => {
+                self.update(None, Some((code, message)));
+                return code as i32;
+            }
+        };
+        let epochs = dg.demes().iter().map(deme_epochs).collect::<Vec<_>>();
+        let migrations = model_migrations(&dg);
+        let pulses = model_pulses(&dg);
+        match demes_forward::ForwardGraph::new(dg, burnin, rounding) {
+            Ok(fgraph) => {
+                self.update(Some(fgraph), None);
+                self.deme_names = deme_names;
+                self.epochs = epochs;
+                self.migrations = migrations;
+                self.migration_matrix.clear();
+                self.pulses = pulses;
+                self.current_pulses.clear();
+                self.ancestry_proportions.clear();
+                ForwardGraphErrorCode::NoError as i32
+            }
+            Err(e) => {
+                let code = match &e {
+                    demes_forward::DemesForwardError::DemesError(_)
+                    | demes_forward::DemesForwardError::InvalidDemeSize(_) => {
+                        ForwardGraphErrorCode::ModelError
+                    }
+                    _ => ForwardGraphErrorCode::from(&e),
+                };
+                self.update(None, Some((code, format!("{}", e))));
+                code as i32
+            }
+        }
+    }
+
+    fn update_state(&mut self, time: f64) -> Result<(), demes_forward::DemesForwardError> {
+        if let Some(fgraph) = &mut self.graph {
+            fgraph.update_state(time)?;
+            self.migration_matrix.clear();
+            self.current_pulses.clear();
+            self.ancestry_proportions.clear();
+            if fgraph.offspring_deme_sizes().is_some() {
+                let num_demes = fgraph.num_demes_in_model();
+                for offspring_deme in 0..num_demes {
+                    if let Some(proportions) = fgraph.ancestry_proportions(offspring_deme) {
+                        self.ancestry_proportions.extend_from_slice(proportions);
+                    }
+                }
+                self.migration_matrix.resize(num_demes * num_demes, 0.0);
+                // The parental generation, backwards in time.
+                let time = fgraph.end_time().value() - 1.0 - time;
+                for migration in &self.migrations {
+                    if time > migration.end_time && time < migration.start_time {
+                        self.migration_matrix[migration.dest * num_demes + migration.source] =
+                            migration.rate;
+                    }
+                }
+                self.current_pulses.extend(
+                    self.pulses
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, pulse)| pulse.time == time)
+                        .map(|(i, _)| i),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Update the state to each time point of the model in turn,
+    // calling `f` after each update until it returns `false`.
+    // Returns whether every time point was visited.
+    fn for_each_generation<F>(&mut self, mut f: F) -> Result<bool, ForwardGraphErrorCode>
+    where
+        F: FnMut(&Self, f64) -> bool,
+    {
+        let end_time = match &self.graph {
+            Some(fgraph) => fgraph.end_time().value(),
+            None => return Err(ForwardGraphErrorCode::GraphNotInitialized),
+        };
+        let mut time = 0.0;
+        while time < end_time {
+            if let Err(e) = self.update_state(time) {
+                let code = ForwardGraphErrorCode::from(&e);
+                self.update(None, Some((code, format!("{}", e))));
+                return Err(code);
+            }
+            if !f(self, time) {
+                return Ok(false);
+            }
+            time += 1.0;
+        }
+        Ok(true)
+    }
+
+    fn update_error(&mut self, error: Option<(ForwardGraphErrorCode, String)>) {
+        self.error_offset = None;
+        self.error_code = match &error {
+            Some((code, _)) => *code,
+            None => ForwardGraphErrorCode::NoError,
+        };
+        self.error = error.map(|(code, e)| {
+            let message = error_message_cstring(&e);
+            set_last_error(code, message.clone());
+            message
         });
     }
 }
@@ -40,11 +1154,9 @@ impl OpaqueForwardGraph {
 /// The pointer is managed by rust and is freed by [`forward_graph_deallocate`].
 #[no_mangle]
 pub extern "C" fn forward_graph_allocate() -> *mut OpaqueForwardGraph {
-    Box::into_raw(Box::new(OpaqueForwardGraph {
-        graph: None,
-        error: None,
-        current_time: None,
-    }))
+    catch_panic(std::ptr::null_mut(), move || {
+        Box::into_raw(Box::new(OpaqueForwardGraph::new()))
+    })
 }
 
 /// # Safety
@@ -56,35 +1168,12 @@ pub unsafe extern "C" fn forward_graph_initialize_from_yaml(
     burnin: f64,
     graph: *mut OpaqueForwardGraph,
 ) -> i32 {
-    if yaml.is_null() {
-        (*graph).update(None, Some("could not convert c_char to String".to_string()));
-        return -1;
-    }
-    let yaml = CStr::from_ptr(yaml);
-    let yaml = match yaml.to_owned().to_str() {
-        Ok(s) => s.to_string(),
-        Err(e) => {
-            (*graph).update(None, Some(format!("{}", e)));
-            return -1;
-        }
-    };
-    let dg = match demes::loads(&yaml) {
-        Ok(graph) => graph,
-        Err(e) => {
-            (*graph).update(None, Some(format!("{}", e)));
-
-            return -1;
-        }
-    };
-    match demes_forward::ForwardGraph::new(
-        dg,
-        burnin,
-        Some(demes_forward::demes::RoundTimeToInteger::F64),
-    ) {
-        Ok(fgraph) => (*graph).update(Some(fgraph), None),
-        Err(e) => (*graph).update(None, Some(format!("{}", e))),
-    };
-    0
+    initialize_graph(
+        graph,
+        ModelInput::String(yaml),
+        ModelFormat::Yaml,
+        InitializationOptions::Burnin(burnin),
+    )
 }
 
 /// # Safety
@@ -97,662 +1186,5180 @@ pub unsafe extern "C" fn forward_graph_initialize_from_yaml_file(
     burnin: f64,
     graph: *mut OpaqueForwardGraph,
 ) -> i32 {
-    let filename_cstr = CStr::from_ptr(file_name);
-    let filename = match filename_cstr.to_str() {
-        Ok(string) => string,
-        Err(e) => {
-            (*graph).update(None, Some(format!("{}", e)));
-            return -1;
-        }
-    };
-    match std::fs::File::open(filename) {
-        Ok(mut file) => {
-            let mut buf = String::default();
-            match file.read_to_string(&mut buf) {
-                Ok(_) => {
-                    let cstring = CString::new(buf).unwrap();
-                    let ptr = cstring.as_ptr();
-                    forward_graph_initialize_from_yaml(ptr, burnin, graph)
-                }
-                Err(e) => {
-                    (*graph).update(None, Some(format!("{}", e)));
-                    -1
-                }
-            }
-        }
-        Err(e) => {
-            (*graph).update(None, Some(format!("{}", e)));
-            -1
-        }
-    }
+    initialize_graph(
+        graph,
+        ModelInput::File(file_name),
+        ModelFormat::Yaml,
+        InitializationOptions::Burnin(burnin),
+    )
 }
 
+/// Initialize from a buffer containing a `YAML` representation
+/// of a `demes` model.
+///
+/// The buffer does not need to be NULL-terminated.
+/// If the buffer is not valid utf8, the byte offset of the
+/// invalid sequence is given by [`forward_graph_get_error_offset`].
+///
 /// # Safety
 ///
-/// `graph` must be a valid pointer
+/// * `data` must point to at least `len` bytes.
+///   It may be NULL if `len` is `0`.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_is_error_state(graph: *const OpaqueForwardGraph) -> bool {
-    (*graph).error.is_some()
+pub unsafe extern "C" fn forward_graph_initialize_from_yaml_buffer(
+    data: *const u8,
+    len: usize,
+    burnin: f64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::Buffer(data, len),
+        ModelFormat::Yaml,
+        InitializationOptions::Burnin(burnin),
+    )
 }
 
+/// Initialize from a `JSON` representation of a `demes` model.
+///
+/// The burn-in and error handling are the same
+/// as for [`forward_graph_initialize_from_yaml`].
+///
 /// # Safety
 ///
-/// `graph` must be a valid pointer
+/// `json` must be a valid pointer containing valid utf8 data.
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_deallocate(graph: *mut OpaqueForwardGraph) {
-    let _ = Box::from_raw(graph);
+pub unsafe extern "C" fn forward_graph_initialize_from_json(
+    json: *const c_char,
+    burnin: f64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::String(json),
+        ModelFormat::Json,
+        InitializationOptions::Burnin(burnin),
+    )
 }
 
+/// Initialize from a buffer containing a `JSON` representation
+/// of a `demes` model.
+///
+/// See [`forward_graph_initialize_from_yaml_buffer`].
+///
 /// # Safety
 ///
-/// `graph` must be a valid pointer
+/// * `data` must point to at least `len` bytes.
+///   It may be NULL if `len` is `0`.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_get_error_message(
-    graph: *const OpaqueForwardGraph,
-    status: *mut i32,
-) -> *const c_char {
-    *status = 0;
-    match &(*graph).error {
-        Some(message) => message.as_ptr(),
-        None => std::ptr::null(),
-    }
+pub unsafe extern "C" fn forward_graph_initialize_from_json_buffer(
+    data: *const u8,
+    len: usize,
+    burnin: f64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::Buffer(data, len),
+        ModelFormat::Json,
+        InitializationOptions::Burnin(burnin),
+    )
 }
 
-/// Pointer to first element of selfing rates array.
+/// Initialize from a file containing a `JSON` representation
+/// of a `demes` model.
 ///
-/// The length of the array is equal to [`forward_graph_number_of_demes`].
+/// # Safety
+///
+/// * `file_name` must be a non-NULL pointer to valid utf8.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_initialize_from_json_file(
+    file_name: *const c_char,
+    burnin: f64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::File(file_name),
+        ModelFormat::Json,
+        InitializationOptions::Burnin(burnin),
+    )
+}
+
+/// Set `options` to the default values.
+///
+/// `version` must be [`FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION`]
+/// as defined in the header that the caller was compiled against.
+/// Only the fields present in that version are written,
+/// and `size` is set to the size of the struct in that version.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`]
+/// if `version` is not supported.
 ///
 /// # Safety
 ///
-/// `graph` must be a valid pointer
+/// `options` must be a valid pointer to a
+/// [`ForwardGraphInitializationOptions`] of the given version.
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_selfing_rates(
-    graph: *const OpaqueForwardGraph,
-    status: *mut i32,
-) -> *const f64 {
-    *status = 0;
-    match &(*graph).graph {
-        Some(graph) => match graph.selfing_rates() {
-            Some(slice) => slice.as_ptr() as *const f64,
-            None => std::ptr::null(),
+pub unsafe extern "C" fn forward_graph_initialization_options_init(
+    options: *mut ForwardGraphInitializationOptions,
+    version: u32,
+) -> i32 {
+    ffi_boundary(
+        std::ptr::null(),
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(options, ForwardGraphErrorCode::NullPointer as i32);
+            let size = match initialization_options_size(version) {
+                Some(size) => size,
+                None => return record_error(ForwardGraphErrorCode::InvalidOptions),
+            };
+            let defaults = ForwardGraphInitializationOptions::default();
+            std::ptr::addr_of_mut!((*options).version).write(version);
+            std::ptr::addr_of_mut!((*options).size).write(size);
+            std::ptr::addr_of_mut!((*options).burnin).write(defaults.burnin);
+            std::ptr::addr_of_mut!((*options).rounding).write(defaults.rounding);
+            std::ptr::addr_of_mut!((*options).require_generations)
+                .write(defaults.require_generations);
+            std::ptr::addr_of_mut!((*options).burnin_units).write(defaults.burnin_units);
+            0
         },
-        None => {
-            *status = -1;
-            std::ptr::null()
-        }
-    }
+    )
 }
 
-/// Pointer to first element of cloning rates array.
+/// Initialize from a `YAML` representation of a `demes` model.
 ///
-/// The length of the array is equal to [`forward_graph_number_of_demes`].
+/// # Safety
+///
+/// * `yaml` must be a valid pointer containing valid utf8 data.
+/// * `options` must be a valid pointer to a
+///   [`ForwardGraphInitializationOptions`] set up by
+///   [`forward_graph_initialization_options_init`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_initialize_from_yaml_with_options(
+    yaml: *const c_char,
+    options: *const ForwardGraphInitializationOptions,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::String(yaml),
+        ModelFormat::Yaml,
+        InitializationOptions::Struct(options),
+    )
+}
+
+/// Initialize from a file containing a `YAML` representation
+/// of a `demes` model.
 ///
 /// # Safety
 ///
-/// `graph` must be a valid pointer
+/// * `file_name` must be a non-NULL pointer to valid utf8.
+/// * `options` must be a valid pointer to a
+///   [`ForwardGraphInitializationOptions`] set up by
+///   [`forward_graph_initialization_options_init`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_cloning_rates(
-    graph: *const OpaqueForwardGraph,
-    status: *mut i32,
-) -> *const f64 {
-    *status = 0;
-    match &(*graph).graph {
-        Some(graph) => match graph.cloning_rates() {
-            Some(slice) => slice.as_ptr() as *const f64,
-            None => std::ptr::null(),
-        },
-        None => {
-            *status = -1;
-            std::ptr::null()
-        }
-    }
+pub unsafe extern "C" fn forward_graph_initialize_from_yaml_file_with_options(
+    file_name: *const c_char,
+    options: *const ForwardGraphInitializationOptions,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::File(file_name),
+        ModelFormat::Yaml,
+        InitializationOptions::Struct(options),
+    )
 }
 
-/// Return a pointer to the first element of parental deme size array.
+/// Initialize from a buffer containing a `YAML` representation
+/// of a `demes` model.
 ///
-/// The length of the array is equal to [`forward_graph_number_of_demes`].
+/// # Safety
+///
+/// * `data` must point to at least `len` bytes.
+///   It may be NULL if `len` is `0`.
+/// * `options` must be a valid pointer to a
+///   [`ForwardGraphInitializationOptions`] set up by
+///   [`forward_graph_initialization_options_init`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_initialize_from_yaml_buffer_with_options(
+    data: *const u8,
+    len: usize,
+    options: *const ForwardGraphInitializationOptions,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::Buffer(data, len),
+        ModelFormat::Yaml,
+        InitializationOptions::Struct(options),
+    )
+}
+
+/// Initialize from a `JSON` representation of a `demes` model.
+///
+/// # Safety
+///
+/// * `json` must be a valid pointer containing valid utf8 data.
+/// * `options` must be a valid pointer to a
+///   [`ForwardGraphInitializationOptions`] set up by
+///   [`forward_graph_initialization_options_init`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_initialize_from_json_with_options(
+    json: *const c_char,
+    options: *const ForwardGraphInitializationOptions,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::String(json),
+        ModelFormat::Json,
+        InitializationOptions::Struct(options),
+    )
+}
+
+/// Initialize from a file containing a `JSON` representation
+/// of a `demes` model.
+///
+/// # Safety
+///
+/// * `file_name` must be a non-NULL pointer to valid utf8.
+/// * `options` must be a valid pointer to a
+///   [`ForwardGraphInitializationOptions`] set up by
+///   [`forward_graph_initialization_options_init`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_initialize_from_json_file_with_options(
+    file_name: *const c_char,
+    options: *const ForwardGraphInitializationOptions,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::File(file_name),
+        ModelFormat::Json,
+        InitializationOptions::Struct(options),
+    )
+}
+
+/// Initialize from a buffer containing a `JSON` representation
+/// of a `demes` model.
+///
+/// # Safety
 ///
+/// * `data` must point to at least `len` bytes.
+///   It may be NULL if `len` is `0`.
+/// * `options` must be a valid pointer to a
+///   [`ForwardGraphInitializationOptions`] set up by
+///   [`forward_graph_initialization_options_init`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_initialize_from_json_buffer_with_options(
+    data: *const u8,
+    len: usize,
+    options: *const ForwardGraphInitializationOptions,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    initialize_graph(
+        graph,
+        ModelInput::Buffer(data, len),
+        ModelFormat::Json,
+        InitializationOptions::Struct(options),
+    )
+}
+
 /// # Safety
 ///
 /// `graph` must be a valid pointer
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_parental_deme_sizes(
+pub unsafe extern "C" fn forward_graph_is_error_state(graph: *const OpaqueForwardGraph) -> bool {
+    ffi_boundary(graph, std::ptr::null_mut(), true, move || {
+        return_if_null!(graph, true);
+        return_if_invalid_handle!(graph, true);
+        (*graph).error.is_some()
+    })
+}
+
+/// Make a deep copy of an [`OpaqueForwardGraph`].
+///
+/// The copy has the same model, the same state set by
+/// [`forward_graph_update_state`], the same position of any
+/// time iteration started by [`forward_graph_initialize_time_iteration`],
+/// and the same error state.
+/// The copy is independent of `graph`.
+///
+/// Cloning is not cheap: it costs about as much as initializing
+/// a graph from a string.
+/// The resolved model cannot be copied or shared, because neither
+/// `demes::Graph` nor `demes_forward::ForwardGraph` can be cloned and
+/// `demes` has no way to build a graph without validating it.
+/// Instead, the model is parsed, validated and converted again from
+/// the input that `graph` was initialized with, and the state is
+/// then updated to the same time.
+/// That input is kept in memory and shared between a graph and its
+/// clones, so no files are read again.
+///
+/// # Returns
+///
+/// A new graph that must be freed by [`forward_graph_deallocate`].
+/// Returns NULL on error, which is reported by
+/// [`demes_forward_last_error_code`].
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_clone(
     graph: *const OpaqueForwardGraph,
-    status: *mut i32,
-) -> *const f64 {
-    *status = 0;
-    match &(*graph).graph {
-        Some(graph) => match graph.parental_deme_sizes() {
-            Some(slice) => slice.as_ptr() as *const f64,
-            None => std::ptr::null(),
+) -> *mut OpaqueForwardGraph {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        move || {
+            return_if_null!(graph, std::ptr::null_mut());
+            return_if_invalid_handle!(graph, std::ptr::null_mut());
+            match (*graph).try_clone() {
+                Ok(clone) => Box::into_raw(Box::new(clone)),
+                Err((code, message)) => {
+                    set_last_error(code, error_message_cstring(&message));
+                    std::ptr::null_mut()
+                }
+            }
         },
-        None => {
-            *status = -1;
-            std::ptr::null()
+    )
+}
+
+/// Free an [`OpaqueForwardGraph`].
+///
+/// Passing NULL is a no-op.
+///
+/// With the `checked-handles` feature, the memory of the handle
+/// itself is never released, so that freeing it again or using
+/// it after it is freed reports [`ForwardGraphErrorCode::InvalidHandle`].
+///
+/// # Safety
+///
+/// `graph` must be NULL or a pointer returned by
+/// [`forward_graph_allocate`] that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_deallocate(graph: *mut OpaqueForwardGraph) {
+    // The graph cannot record a panic
+    // that happens while it is being freed.
+    catch_panic((), move || {
+        if graph.is_null() {
+            return;
         }
-    }
+        #[cfg(feature = "checked-handles")]
+        {
+            if invalid_handle(graph, std::ptr::null_mut()) {
+                return;
+            }
+            // Free the contents but keep the handle itself
+            // so that later uses of it are detected.
+            let generation = (*graph).tag.generation;
+            *graph = OpaqueForwardGraph::new();
+            (*graph).tag = HandleTag {
+                magic: FREED_HANDLE,
+                generation,
+            };
+        }
+        #[cfg(not(feature = "checked-handles"))]
+        let _ = Box::from_raw(graph);
+    })
 }
 
-/// Return a pointer to the first element of offspring deme size array.
+/// Get the error message.
 ///
-/// The length of the array is equal to [`forward_graph_number_of_demes`].
+/// The message is utf8 encoded and NULL-terminated.
+///
+/// # Returns
+///
+/// NULL if the graph is not in an error state.
 ///
 /// # Safety
 ///
 /// `graph` must be a valid pointer
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_offspring_deme_sizes(
+pub unsafe extern "C" fn forward_graph_get_error_message(
     graph: *const OpaqueForwardGraph,
     status: *mut i32,
-) -> *const f64 {
-    *status = 0;
-    match &(*graph).graph {
-        Some(graph) => match graph.offspring_deme_sizes() {
-            Some(slice) => slice.as_ptr() as *const f64,
+) -> *const c_char {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        match &(*graph).error {
+            Some(message) => message.as_ptr(),
             None => std::ptr::null(),
-        },
-        None => {
-            *status = -1;
-            std::ptr::null()
         }
-    }
+    })
 }
 
-/// Check if there are any extant offspring demes.
+/// Get the length of the error message.
+///
+/// # Returns
+///
+/// The number of bytes in the message returned by
+/// [`forward_graph_get_error_message`], not including
+/// the terminating NULL byte.
+/// Returns `0` if the graph is not in an error state.
 ///
 /// # Safety
 ///
 /// `graph` must be a valid pointer
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_any_extant_offspring_demes(
+pub unsafe extern "C" fn forward_graph_get_error_message_length(
     graph: *const OpaqueForwardGraph,
     status: *mut i32,
-) -> bool {
-    *status = 0;
-    match &(*graph).graph {
-        Some(graph) => graph.any_extant_offspring_demes(),
-        None => {
-            *status = -1;
-            false
+) -> usize {
+    ffi_boundary(graph, status, 0, move || {
+        return_if_null!(status, 0);
+        *status = 0;
+        return_if_null!(graph, status, 0);
+        return_if_invalid_handle!(graph, status, 0);
+        match &(*graph).error {
+            Some(message) => message.as_bytes().len(),
+            None => 0,
         }
-    }
+    })
 }
 
-/// Check if there are any extant parental demes.
+/// Get the byte offset of the invalid utf8 sequence
+/// that caused the current error.
+///
+/// The offset is only available when a buffer passed to
+/// [`forward_graph_initialize_from_yaml_buffer`] or one of
+/// its variants is not valid utf8.
+///
+/// # Returns
+///
+/// The offset in bytes from the start of the buffer,
+/// or `-1` if the current error has no offset.
 ///
 /// # Safety
 ///
 /// `graph` must be a valid pointer
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_any_extant_parent_demes(
+pub unsafe extern "C" fn forward_graph_get_error_offset(
     graph: *const OpaqueForwardGraph,
     status: *mut i32,
-) -> bool {
-    *status = 0;
-    match &(*graph).graph {
-        Some(graph) => graph.any_extant_parental_demes(),
-        None => {
-            *status = -1;
-            false
+) -> i64 {
+    ffi_boundary(graph, status, -1, move || {
+        return_if_null!(status, -1);
+        *status = 0;
+        return_if_null!(graph, status, -1);
+        return_if_invalid_handle!(graph, status, -1);
+        match (*graph).error_offset {
+            Some(offset) => offset as i64,
+            None => -1,
         }
-    }
+    })
 }
 
-/// Get the total number of demes in the model
+/// Get the code of the last error recorded on the graph.
 ///
 /// # Returns
 ///
-/// [`isize`] > 0 if the graph is not in an error state.
-/// Returns `-1` otherwise.
+/// [`ForwardGraphErrorCode::NoError`] if the graph is not
+/// in an error state.
 ///
 /// # Safety
 ///
 /// `graph` must be a valid pointer
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_number_of_demes(graph: *const OpaqueForwardGraph) -> isize {
-    match &(*graph).graph {
-        Some(graph) => graph.num_demes_in_model() as isize,
-        None => -1,
-    }
+pub unsafe extern "C" fn forward_graph_get_error_code(
+    graph: *const OpaqueForwardGraph,
+) -> ForwardGraphErrorCode {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle);
+            (*graph).error_code
+        },
+    )
 }
 
-/// Update the model state to a given time.
+/// Get the code of the last error recorded on the calling thread.
 ///
-/// # Safety
+/// Every function in this library records its errors here,
+/// including errors that occur without a valid graph.
+/// Successful calls do not reset the last error.
 ///
-/// `graph` must be a valid pointer
+/// # Returns
+///
+/// [`ForwardGraphErrorCode::NoError`] if no error has been
+/// recorded since the thread started or since the last call to
+/// [`demes_forward_clear_last_error`].
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_update_state(
-    time: f64,
-    graph: *mut OpaqueForwardGraph,
-) -> i32 {
-    match &mut (*graph).graph {
-        Some(fgraph) => match fgraph.update_state(time) {
-            Ok(()) => 0,
-            Err(e) => {
-                (*graph).update(None, Some(format!("{}", e)));
-                -1
-            }
-        },
-        None => -1,
-    }
+pub extern "C" fn demes_forward_last_error_code() -> ForwardGraphErrorCode {
+    catch_panic(ForwardGraphErrorCode::Panic, move || {
+        LAST_ERROR.with(|last| match &*last.borrow() {
+            Some((code, _)) => *code,
+            None => ForwardGraphErrorCode::NoError,
+        })
+    })
 }
 
-/// Initialize graph to begin iterating over model.
+/// Get the message of the last error recorded on the calling thread.
 ///
-/// # Safety
+/// The message is utf8 encoded and NULL-terminated.
 ///
-/// `graph` must be a valid pointer
+/// # Returns
+///
+/// NULL if no error has been recorded.
+/// The pointer remains valid until the next error is
+/// recorded on the calling thread or until
+/// [`demes_forward_clear_last_error`] is called.
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_initialize_time_iteration(
-    graph: *mut OpaqueForwardGraph,
-) -> i32 {
-    match &mut (*graph).graph {
-        Some(fgraph) => {
-            match fgraph.last_time_updated() {
-                Some(value) => {
-                    (*graph).current_time = Some(value.value() - 1.0);
-                }
-                None => {
-                    (*graph).current_time = Some(-1.0);
-                }
-            }
-            0
-        }
-        None => -1,
-    }
+pub extern "C" fn demes_forward_last_error_message() -> *const c_char {
+    catch_panic(std::ptr::null(), move || {
+        LAST_ERROR.with(|last| match &*last.borrow() {
+            Some((_, message)) => message.as_ptr(),
+            None => std::ptr::null(),
+        })
+    })
 }
 
-/// Iterate to the next time point in the model.
+/// Clear the last error recorded on the calling thread.
+#[no_mangle]
+pub extern "C" fn demes_forward_clear_last_error() {
+    catch_panic((), move || {
+        LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    })
+}
+
+/// Check if panics inside the library are caught.
 ///
-/// # Return values:
+/// # Returns
 ///
-/// * null = done iterating
-/// * not null = still iterating
+/// `true` if the library was built with `panic = "unwind"`,
+/// for example with `cargo build --profile release-unwind`.
+/// Panics are then reported as [`ForwardGraphErrorCode::Panic`].
+/// Returns `false` if the library was built with `panic = "abort"`,
+/// which is the case for the `release` profile.
+/// Panics then abort the process.
+#[no_mangle]
+pub extern "C" fn forward_graph_catch_panics() -> bool {
+    cfg!(panic = "unwind")
+}
+
+/// Pointer to first element of selfing rates array.
+///
+/// The length of the array is equal to [`forward_graph_number_of_demes`].
 ///
 /// # Safety
 ///
 /// `graph` must be a valid pointer
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_iterate_time(
-    graph: *mut OpaqueForwardGraph,
+pub unsafe extern "C" fn forward_graph_selfing_rates(
+    graph: *const OpaqueForwardGraph,
     status: *mut i32,
 ) -> *const f64 {
-    *status = 0;
-    if (*graph).current_time.is_none() {
-        *status = -1;
-        (*graph).update_error(Some(
-            "forward_graph_initialize_time_iteration has not been called".to_string(),
-        ));
-        return std::ptr::null();
-    }
-    let tref: &mut f64 = (*graph).current_time.as_mut().unwrap();
-    match &mut (*graph).graph {
-        Some(fgraph) => {
-            if *tref < fgraph.end_time().value() - 1.0 {
-                *tref += 1.0;
-                &*tref
-            } else {
-                (*graph).current_time = None;
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        match &(*graph).graph {
+            Some(graph) => match graph.selfing_rates() {
+                Some(slice) => slice.as_ptr() as *const f64,
+                None => std::ptr::null(),
+            },
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
                 std::ptr::null()
             }
         }
-        None => {
-            *status = -1;
-            std::ptr::null()
-        }
-    }
+    })
 }
 
+/// Pointer to first element of cloning rates array.
+///
+/// The length of the array is equal to [`forward_graph_number_of_demes`].
+///
 /// # Safety
 ///
-/// `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
-/// `status` must be a valid pointer to an `i32`.
+/// `graph` must be a valid pointer
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_ancestry_proportions(
-    offspring_deme: usize,
+pub unsafe extern "C" fn forward_graph_cloning_rates(
+    graph: *const OpaqueForwardGraph,
     status: *mut i32,
-    graph: *mut OpaqueForwardGraph,
 ) -> *const f64 {
-    *status = 0;
-    if (*graph).error.is_some() {
-        *status = -1;
-        return std::ptr::null();
-    }
-    match &(*graph).graph {
-        Some(fgraph) => {
-            if offspring_deme >= fgraph.num_demes_in_model() {
-                *status = -1;
-                (*graph).update_error(Some(format!(
-                    "offspring deme index {} out of range",
-                    offspring_deme
-                )));
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        match &(*graph).graph {
+            Some(graph) => match graph.cloning_rates() {
+                Some(slice) => slice.as_ptr() as *const f64,
+                None => std::ptr::null(),
+            },
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
                 std::ptr::null()
-            } else {
-                match fgraph.ancestry_proportions(offspring_deme) {
-                    Some(proportions) => proportions.as_ptr() as *const f64,
-                    None => std::ptr::null(),
-                }
             }
         }
-        None => {
-            *status = -1;
-            std::ptr::null()
-        }
-    }
+    })
 }
 
-/// Get the model end time.
+/// Return a pointer to the first element of parental deme size array.
 ///
-/// The value returned is one generation after the
-/// last parental generation.
-/// Thus, this value defines a half-open interval
-/// during which parental demes exist.
+/// The length of the array is equal to [`forward_graph_number_of_demes`].
 ///
 /// # Safety
 ///
-/// `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
-/// `status` must be a valid pointer to an `i32`.
+/// `graph` must be a valid pointer
 #[no_mangle]
-pub unsafe extern "C" fn forward_graph_model_end_time(
+pub unsafe extern "C" fn forward_graph_parental_deme_sizes(
+    graph: *const OpaqueForwardGraph,
     status: *mut i32,
+) -> *const f64 {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        match &(*graph).graph {
+            Some(graph) => match graph.parental_deme_sizes() {
+                Some(slice) => slice.as_ptr() as *const f64,
+                None => std::ptr::null(),
+            },
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                std::ptr::null()
+            }
+        }
+    })
+}
+
+/// Return a pointer to the first element of offspring deme size array.
+///
+/// The length of the array is equal to [`forward_graph_number_of_demes`].
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_offspring_deme_sizes(
     graph: *const OpaqueForwardGraph,
-) -> f64 {
-    *status = 0;
-    if (*graph).error.is_some() || (*graph).graph.is_none() {
-        *status = -1;
-        f64::NAN
-    } else {
+    status: *mut i32,
+) -> *const f64 {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
         match &(*graph).graph {
-            Some(fgraph) => fgraph.end_time().value(),
+            Some(graph) => match graph.offspring_deme_sizes() {
+                Some(slice) => slice.as_ptr() as *const f64,
+                None => std::ptr::null(),
+            },
             None => {
-                *status = -1;
-                f64::NAN
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                std::ptr::null()
             }
         }
+    })
+}
+
+// The splitmix64 generator.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform on [0, 1).
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::{ffi::CString, io::Write};
+/// A source of random numbers for the stochastic
+/// functions of this library.
+///
+/// `uniform` is called with `state` and must return a number
+/// uniformly distributed on `[0, 1)`.
+/// [`forward_graph_default_rng`] gives a built-in generator.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ForwardGraphRng {
+    /// Draws a number uniformly distributed on `[0, 1)`.
+    pub uniform: Option<unsafe extern "C" fn(state: *mut c_void) -> f64>,
+    /// The state of the generator, passed to `uniform`.
+    pub state: *mut c_void,
+}
 
-    struct GraphHolder {
-        graph: *mut OpaqueForwardGraph,
+impl ForwardGraphRng {
+    unsafe fn uniform(&self) -> Result<f64, (ForwardGraphErrorCode, String)> {
+        // Checked by read_rng.
+        let uniform = self.uniform.unwrap();
+        let value = uniform(self.state);
+        if (0.0..1.0).contains(&value) {
+            Ok(value)
+        } else {
+            Err((
+                ForwardGraphErrorCode::InvalidArgument,
+                format!(
+                    "random number generator returned {}, which is not in [0, 1)",
+                    value
+                ),
+            ))
+        }
+    }
+}
+
+unsafe fn read_rng(
+    rng: *const ForwardGraphRng,
+) -> Result<ForwardGraphRng, (ForwardGraphErrorCode, String)> {
+    if rng.is_null() || (*rng).uniform.is_none() {
+        return Err((
+            ForwardGraphErrorCode::NullPointer,
+            "random number generator is NULL".to_string(),
+        ));
     }
+    Ok(*rng)
+}
 
-    impl GraphHolder {
-        fn new() -> Self {
-            Self {
-                graph: forward_graph_allocate(),
+/// The built-in random number generator.
+///
+/// Allocated by [`forward_graph_default_rng_allocate`]
+/// and used through [`forward_graph_default_rng`].
+pub struct OpaqueDefaultRng(SplitMix64);
+
+/// Allocate the built-in random number generator.
+///
+/// The same `seed` always gives the same sequence of numbers.
+///
+/// # Returns
+///
+/// A new generator that must be freed by
+/// [`forward_graph_default_rng_deallocate`].
+#[no_mangle]
+pub extern "C" fn forward_graph_default_rng_allocate(seed: u64) -> *mut OpaqueDefaultRng {
+    catch_panic(std::ptr::null_mut(), move || {
+        Box::into_raw(Box::new(OpaqueDefaultRng(SplitMix64(seed))))
+    })
+}
+
+/// Free the built-in random number generator.
+///
+/// Passing NULL is a no-op.
+///
+/// # Safety
+///
+/// `rng` must be NULL or a pointer returned by
+/// [`forward_graph_default_rng_allocate`] that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_default_rng_deallocate(rng: *mut OpaqueDefaultRng) {
+    catch_panic((), move || {
+        if !rng.is_null() {
+            let _ = Box::from_raw(rng);
+        }
+    })
+}
+
+/// Draw a number uniformly distributed on `[0, 1)`
+/// from the built-in random number generator.
+///
+/// This is the `uniform` function of the
+/// [`ForwardGraphRng`] returned by [`forward_graph_default_rng`].
+///
+/// # Returns
+///
+/// NaN if `state` is NULL.
+///
+/// # Safety
+///
+/// `state` must be NULL or a pointer returned by
+/// [`forward_graph_default_rng_allocate`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_default_rng_uniform(state: *mut c_void) -> f64 {
+    catch_panic(f64::NAN, move || {
+        let rng = state as *mut OpaqueDefaultRng;
+        return_if_null!(rng, f64::NAN);
+        (*rng).0.uniform()
+    })
+}
+
+/// Get a [`ForwardGraphRng`] that draws from
+/// the built-in random number generator.
+///
+/// # Safety
+///
+/// `rng` must be a pointer returned by
+/// [`forward_graph_default_rng_allocate`] and must
+/// not be freed while the result is in use.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_default_rng(rng: *mut OpaqueDefaultRng) -> ForwardGraphRng {
+    ForwardGraphRng {
+        uniform: Some(forward_graph_default_rng_uniform),
+        state: rng as *mut c_void,
+    }
+}
+
+/// How deme sizes are rounded to integers.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardGraphSizeRounding {
+    /// Round to the nearest integer, with ties to the even integer.
+    HalfEven = 0,
+    /// Round down.
+    Floor = 1,
+    /// Round up.
+    Ceil = 2,
+    /// Round up with probability equal to the fractional part
+    /// and down otherwise, so that the expected value is the
+    /// unrounded size.
+    Stochastic = 3,
+}
+
+impl_try_from_i32!(ForwardGraphSizeRounding, HalfEven, Floor, Ceil, Stochastic);
+
+// Convert an enum passed by value from C,
+// whose type is given to C as an integer.
+fn read_enum_argument<T: TryFrom<i32, Error = i32>>(
+    value: i32,
+    name: &str,
+) -> Result<T, (ForwardGraphErrorCode, String)> {
+    T::try_from(value).map_err(|value| {
+        (
+            ForwardGraphErrorCode::InvalidArgument,
+            format!("invalid value {} for {}", value, name),
+        )
+    })
+}
+
+// `rng` is only used, and only needs to be set,
+// for stochastic rounding.
+unsafe fn round_deme_size(
+    size: f64,
+    rounding: ForwardGraphSizeRounding,
+    rng: Option<&ForwardGraphRng>,
+) -> Result<f64, (ForwardGraphErrorCode, String)> {
+    let rv = match rounding {
+        ForwardGraphSizeRounding::HalfEven => size.round_ties_even(),
+        ForwardGraphSizeRounding::Floor => size.floor(),
+        ForwardGraphSizeRounding::Ceil => size.ceil(),
+        ForwardGraphSizeRounding::Stochastic => {
+            let floor = size.floor();
+            // Do not draw for sizes that are already integers.
+            if size > floor && rng.unwrap().uniform()? < size - floor {
+                floor + 1.0
+            } else {
+                floor
             }
         }
+    };
+    Ok(rv)
+}
 
-        fn as_mut_ptr(&mut self) -> *mut OpaqueForwardGraph {
-            self.graph
+#[derive(Copy, Clone)]
+enum DemeSizes {
+    Parental,
+    Offspring,
+}
+
+// Round the sizes of all demes to integers,
+// using zero for demes that are not in `values`.
+// `rng` is only read for stochastic rounding.
+unsafe fn round_deme_sizes<T: TryFrom<u64>>(
+    values: &[f64],
+    num_demes: usize,
+    rounding: ForwardGraphSizeRounding,
+    rng: *const ForwardGraphRng,
+) -> Result<Vec<T>, (ForwardGraphErrorCode, String)> {
+    let rng = if rounding == ForwardGraphSizeRounding::Stochastic {
+        Some(read_rng(rng)?)
+    } else {
+        None
+    };
+    (0..num_demes)
+        .map(|deme| {
+            let size = values.get(deme).copied().unwrap_or(0.0);
+            let rounded = round_deme_size(size, rounding, rng.as_ref())?;
+            // The cast saturates, so values of u64::MAX
+            // or more are always an overflow.
+            match T::try_from(rounded as u64) {
+                Ok(value) if rounded < u64::MAX as f64 => Ok(value),
+                _ => Err((
+                    ForwardGraphErrorCode::IntegerOverflow,
+                    format!("size {} of deme {} is too large", rounded, deme),
+                )),
+            }
+        })
+        .collect()
+}
+
+// Record an error that does not change
+// the state of the graph and return its code.
+fn report_error((code, message): (ForwardGraphErrorCode, String)) -> i32 {
+    set_last_error(code, error_message_cstring(&message));
+    code as i32
+}
+
+// The implementation of the integer deme size functions.
+unsafe fn integer_deme_sizes<T: TryFrom<u64>>(
+    which: DemeSizes,
+    rounding: i32,
+    rng: *const ForwardGraphRng,
+    sizes: *mut T,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            return_if_null!(sizes, ForwardGraphErrorCode::NullPointer as i32);
+            let rounding = match read_enum_argument(rounding, "rounding") {
+                Ok(rounding) => rounding,
+                Err(error) => return report_error(error),
+            };
+            let fgraph = match &(*graph).graph {
+                Some(fgraph) => fgraph,
+                None => return record_error(ForwardGraphErrorCode::GraphNotInitialized),
+            };
+            let num_demes = fgraph.num_demes_in_model();
+            let values = match which {
+                DemeSizes::Parental => f64_values(fgraph.parental_deme_sizes()),
+                DemeSizes::Offspring => f64_values(fgraph.offspring_deme_sizes()),
+            };
+            match round_deme_sizes::<T>(values, num_demes, rounding, rng) {
+                Ok(rounded) => {
+                    let sizes = std::slice::from_raw_parts_mut(sizes, num_demes);
+                    for (output, value) in sizes.iter_mut().zip(rounded) {
+                        *output = value;
+                    }
+                    0
+                }
+                Err(error) => report_error(error),
+            }
+        },
+    )
+}
+
+/// Fill `sizes` with the parental deme sizes
+/// rounded to integers using `rounding`,
+/// which is a [`ForwardGraphSizeRounding`].
+///
+/// Demes that do not exist have a size of `0`.
+/// With [`ForwardGraphSizeRounding::Stochastic`], random
+/// numbers are drawn from `rng`.
+/// `rng` is not used, and may be NULL, otherwise.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`] otherwise.
+///
+/// # Safety
+///
+/// * `rng` must be NULL or a valid pointer to a [`ForwardGraphRng`].
+/// * `sizes` must be valid for [`forward_graph_number_of_demes`] values.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parental_deme_sizes_u32(
+    rounding: i32,
+    rng: *const ForwardGraphRng,
+    sizes: *mut u32,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    integer_deme_sizes(DemeSizes::Parental, rounding, rng, sizes, graph)
+}
+
+/// As [`forward_graph_parental_deme_sizes_u32`], but for `u64` sizes.
+///
+/// # Safety
+///
+/// * `rng` must be NULL or a valid pointer to a [`ForwardGraphRng`].
+/// * `sizes` must be valid for [`forward_graph_number_of_demes`] values.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parental_deme_sizes_u64(
+    rounding: i32,
+    rng: *const ForwardGraphRng,
+    sizes: *mut u64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    integer_deme_sizes(DemeSizes::Parental, rounding, rng, sizes, graph)
+}
+
+/// Fill `sizes` with the offspring deme sizes
+/// rounded to integers using `rounding`,
+/// which is a [`ForwardGraphSizeRounding`].
+///
+/// See [`forward_graph_parental_deme_sizes_u32`].
+/// All sizes are `0` if there are no offspring demes.
+///
+/// # Safety
+///
+/// * `rng` must be NULL or a valid pointer to a [`ForwardGraphRng`].
+/// * `sizes` must be valid for [`forward_graph_number_of_demes`] values.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_offspring_deme_sizes_u32(
+    rounding: i32,
+    rng: *const ForwardGraphRng,
+    sizes: *mut u32,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    integer_deme_sizes(DemeSizes::Offspring, rounding, rng, sizes, graph)
+}
+
+/// As [`forward_graph_offspring_deme_sizes_u32`], but for `u64` sizes.
+///
+/// # Safety
+///
+/// * `rng` must be NULL or a valid pointer to a [`ForwardGraphRng`].
+/// * `sizes` must be valid for [`forward_graph_number_of_demes`] values.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_offspring_deme_sizes_u64(
+    rounding: i32,
+    rng: *const ForwardGraphRng,
+    sizes: *mut u64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    integer_deme_sizes(DemeSizes::Offspring, rounding, rng, sizes, graph)
+}
+
+/// Return a pointer to the first element of the migration matrix.
+///
+/// The matrix contains the continuous migration rates
+/// for the state set by [`forward_graph_update_state`].
+/// It is stored in row-major order with
+/// [`forward_graph_number_of_demes`] rows and columns.
+/// Rows are offspring demes and columns are source (parental) demes.
+/// Pulses and ancestry from deme splits are not included.
+///
+/// # Returns
+///
+/// NULL if there are no offspring demes.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_migration_matrix(
+    graph: *const OpaqueForwardGraph,
+    status: *mut i32,
+) -> *const f64 {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        if (*graph).graph.is_none() {
+            set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+            return std::ptr::null();
+        }
+        if (*graph).migration_matrix.is_empty() {
+            std::ptr::null()
+        } else {
+            (*graph).migration_matrix.as_ptr()
         }
+    })
+}
 
-        fn as_ptr(&mut self) -> *const OpaqueForwardGraph {
-            self.graph
+/// Get the total number of pulses in the model
+///
+/// # Returns
+///
+/// [`isize`] >= 0 if the graph is not in an error state.
+/// Returns `-1` otherwise.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_number_of_pulses(graph: *const OpaqueForwardGraph) -> isize {
+    ffi_boundary(graph, std::ptr::null_mut(), -1, move || {
+        return_if_null!(graph, -1);
+        return_if_invalid_handle!(graph, -1);
+        match &(*graph).graph {
+            Some(_) => (*graph).pulses.len() as isize,
+            None => {
+                record_error(ForwardGraphErrorCode::GraphNotInitialized);
+                -1
+            }
+        }
+    })
+}
+
+/// Fill `pulse` with the details of a pulse in the model.
+///
+/// Pulses are indexed in the order that they appear in the model,
+/// which is the order in which they are applied when several
+/// occur in the same generation.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`]
+/// if `pulse_index` is out of range.
+///
+/// # Safety
+///
+/// * `pulse` must be a valid pointer to a [`ForwardGraphPulse`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_pulse(
+    pulse_index: usize,
+    pulse: *mut ForwardGraphPulse,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            return_if_null!(pulse, ForwardGraphErrorCode::NullPointer as i32);
+            let end_time = match &(*graph).graph {
+                Some(fgraph) => fgraph.end_time().value(),
+                None => return record_error(ForwardGraphErrorCode::GraphNotInitialized),
+            };
+            match (*graph).pulses.as_slice().get(pulse_index) {
+                Some(details) => {
+                    *pulse = ForwardGraphPulse {
+                        time: end_time - 1.0 - details.time,
+                        dest: details.dest,
+                        num_sources: details.sources.len(),
+                        sources: details.sources.as_ptr(),
+                        proportions: details.proportions.as_ptr(),
+                    };
+                    0
+                }
+                None => {
+                    set_last_error(
+                        ForwardGraphErrorCode::IndexOutOfRange,
+                        error_message_cstring(&format!("pulse index {} out of range", pulse_index)),
+                    );
+                    ForwardGraphErrorCode::IndexOutOfRange as i32
+                }
+            }
+        },
+    )
+}
+
+/// Get the pulses affecting offspring for the state set by
+/// [`forward_graph_update_state`].
+///
+/// The values are indexes that can be passed to
+/// [`forward_graph_pulse`], in the order in which the
+/// pulses are applied.
+/// The number of values is written to `num_pulses`.
+///
+/// # Returns
+///
+/// NULL if there are no pulses in the current generation.
+///
+/// # Safety
+///
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// * `num_pulses` must be a valid pointer to a `usize`.
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_current_pulses(
+    graph: *const OpaqueForwardGraph,
+    num_pulses: *mut usize,
+    status: *mut i32,
+) -> *const usize {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        return_if_null!(num_pulses, status, std::ptr::null());
+        *num_pulses = 0;
+        if (*graph).graph.is_none() {
+            set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+            return std::ptr::null();
+        }
+        if (*graph).current_pulses.is_empty() {
+            std::ptr::null()
+        } else {
+            *num_pulses = (*graph).current_pulses.len();
+            (*graph).current_pulses.as_ptr()
+        }
+    })
+}
+
+/// Check if there are any extant offspring demes.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_any_extant_offspring_demes(
+    graph: *const OpaqueForwardGraph,
+    status: *mut i32,
+) -> bool {
+    ffi_boundary(graph, status, false, move || {
+        return_if_null!(status, false);
+        *status = 0;
+        return_if_null!(graph, status, false);
+        return_if_invalid_handle!(graph, status, false);
+        match &(*graph).graph {
+            Some(graph) => graph.any_extant_offspring_demes(),
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                false
+            }
+        }
+    })
+}
+
+/// Check if there are any extant parental demes.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_any_extant_parent_demes(
+    graph: *const OpaqueForwardGraph,
+    status: *mut i32,
+) -> bool {
+    ffi_boundary(graph, status, false, move || {
+        return_if_null!(status, false);
+        *status = 0;
+        return_if_null!(graph, status, false);
+        return_if_invalid_handle!(graph, status, false);
+        match &(*graph).graph {
+            Some(graph) => graph.any_extant_parental_demes(),
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                false
+            }
+        }
+    })
+}
+
+/// Get the total number of demes in the model
+///
+/// # Returns
+///
+/// [`isize`] > 0 if the graph is not in an error state.
+/// Returns `-1` otherwise.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_number_of_demes(graph: *const OpaqueForwardGraph) -> isize {
+    ffi_boundary(graph, std::ptr::null_mut(), -1, move || {
+        return_if_null!(graph, -1);
+        return_if_invalid_handle!(graph, -1);
+        match &(*graph).graph {
+            Some(graph) => graph.num_demes_in_model() as isize,
+            None => {
+                record_error(ForwardGraphErrorCode::GraphNotInitialized);
+                -1
+            }
+        }
+    })
+}
+
+/// Get the name of a deme.
+///
+/// # Returns
+///
+/// A pointer to a NULL-terminated string if `deme` is a valid
+/// deme index.
+/// The pointer is owned by the graph and remains valid until
+/// the graph is re-initialized or deallocated.
+/// Returns NULL and sets `status` to a negative
+/// [`ForwardGraphErrorCode`] otherwise.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_deme_name(
+    deme: usize,
+    status: *mut i32,
+    graph: *mut OpaqueForwardGraph,
+) -> *const c_char {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        if (*graph).graph.is_none() {
+            set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+            return std::ptr::null();
+        }
+        match (*graph).deme_names.as_slice().get(deme) {
+            Some(name) => name.as_ptr(),
+            None => {
+                *status = ForwardGraphErrorCode::IndexOutOfRange as i32;
+                set_last_error(
+                    ForwardGraphErrorCode::IndexOutOfRange,
+                    error_message_cstring(&format!("deme index {} out of range", deme)),
+                );
+                std::ptr::null()
+            }
+        }
+    })
+}
+
+/// Get the index of a deme from its name.
+///
+/// # Returns
+///
+/// The index of the deme, which is a valid index into
+/// the arrays returned by other functions.
+/// Returns `-1` and sets `status` to
+/// [`ForwardGraphErrorCode::UnknownDeme`] if
+/// `name` is not a deme in the model.
+///
+/// # Safety
+///
+/// * `name` must be a non-NULL pointer to valid utf8.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_deme_index(
+    name: *const c_char,
+    status: *mut i32,
+    graph: *mut OpaqueForwardGraph,
+) -> isize {
+    ffi_boundary(graph, status, -1, move || {
+        return_if_null!(status, -1);
+        *status = 0;
+        return_if_null!(graph, status, -1);
+        return_if_invalid_handle!(graph, status, -1);
+        return_if_null!(name, status, -1);
+        if (*graph).graph.is_none() {
+            set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+            return -1;
+        }
+        let name = CStr::from_ptr(name);
+        match (*graph)
+            .deme_names
+            .iter()
+            .position(|deme_name| deme_name.as_c_str() == name)
+        {
+            Some(index) => index as isize,
+            None => {
+                *status = ForwardGraphErrorCode::UnknownDeme as i32;
+                set_last_error(
+                    ForwardGraphErrorCode::UnknownDeme,
+                    error_message_cstring(&format!(
+                        "deme {} not found in model",
+                        name.to_string_lossy()
+                    )),
+                );
+                -1
+            }
+        }
+    })
+}
+
+/// Get the number of epochs of a deme.
+///
+/// # Returns
+///
+/// The number of epochs if `deme` is a valid deme index.
+/// Returns `-1` and sets `status` to a negative
+/// [`ForwardGraphErrorCode`] otherwise.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_number_of_epochs(
+    deme: usize,
+    status: *mut i32,
+    graph: *mut OpaqueForwardGraph,
+) -> isize {
+    ffi_boundary(graph, status, -1, move || {
+        return_if_null!(status, -1);
+        *status = 0;
+        return_if_null!(graph, status, -1);
+        return_if_invalid_handle!(graph, status, -1);
+        if (*graph).graph.is_none() {
+            set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+            return -1;
+        }
+        match (*graph).epochs.as_slice().get(deme) {
+            Some(epochs) => epochs.len() as isize,
+            None => {
+                *status = ForwardGraphErrorCode::IndexOutOfRange as i32;
+                set_last_error(
+                    ForwardGraphErrorCode::IndexOutOfRange,
+                    error_message_cstring(&format!("deme index {} out of range", deme)),
+                );
+                -1
+            }
+        }
+    })
+}
+
+/// Fill `epoch` with the details of an epoch of a deme.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`]
+/// if `deme` or `epoch_index` is out of range.
+///
+/// # Safety
+///
+/// * `epoch` must be a valid pointer to a [`ForwardGraphEpoch`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_epoch(
+    deme: usize,
+    epoch_index: usize,
+    epoch: *mut ForwardGraphEpoch,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            return_if_null!(epoch, ForwardGraphErrorCode::NullPointer as i32);
+            if (*graph).graph.is_none() {
+                return record_error(ForwardGraphErrorCode::GraphNotInitialized);
+            }
+            match (*graph)
+                .epochs
+                .as_slice()
+                .get(deme)
+                .and_then(|epochs| epochs.get(epoch_index))
+            {
+                Some(details) => {
+                    *epoch = *details;
+                    0
+                }
+                None => {
+                    set_last_error(
+                        ForwardGraphErrorCode::IndexOutOfRange,
+                        error_message_cstring(&format!(
+                            "epoch {} of deme {} out of range",
+                            epoch_index, deme
+                        )),
+                    );
+                    ForwardGraphErrorCode::IndexOutOfRange as i32
+                }
+            }
+        },
+    )
+}
+
+/// Update the model state to a given time.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_update_state(
+    time: f64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            if (*graph).graph.is_none() {
+                return record_error(ForwardGraphErrorCode::GraphNotInitialized);
+            }
+            match (*graph).update_state(time) {
+                Ok(()) => 0,
+                Err(e) => {
+                    let code = ForwardGraphErrorCode::from(&e);
+                    (*graph).update(None, Some((code, format!("{}", e))));
+                    code as i32
+                }
+            }
+        },
+    )
+}
+
+/// Initialize graph to begin iterating over model.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_initialize_time_iteration(
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            match &mut (*graph).graph {
+                Some(fgraph) => {
+                    match fgraph.last_time_updated() {
+                        Some(value) => {
+                            (*graph).current_time = Some(value.value() - 1.0);
+                        }
+                        None => {
+                            (*graph).current_time = Some(-1.0);
+                        }
+                    }
+                    0
+                }
+                None => record_error(ForwardGraphErrorCode::GraphNotInitialized),
+            }
+        },
+    )
+}
+
+/// Iterate to the next time point in the model.
+///
+/// # Return values:
+///
+/// * null = done iterating
+/// * not null = still iterating
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_iterate_time(
+    graph: *mut OpaqueForwardGraph,
+    status: *mut i32,
+) -> *const f64 {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        if (*graph).current_time.is_none() {
+            set_status(status, ForwardGraphErrorCode::IterationNotInitialized);
+            return std::ptr::null();
+        }
+        let tref: &mut f64 = (*graph).current_time.as_mut().unwrap();
+        match &mut (*graph).graph {
+            Some(fgraph) => {
+                if *tref < fgraph.end_time().value() - 1.0 {
+                    *tref += 1.0;
+                    &*tref
+                } else {
+                    (*graph).current_time = None;
+                    std::ptr::null()
+                }
+            }
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                std::ptr::null()
+            }
+        }
+    })
+}
+
+/// A function called once per generation by [`forward_graph_run`].
+///
+/// The arguments are the time, the number of demes,
+/// the parental deme sizes, the offspring deme sizes,
+/// the ancestry proportions matrix and the `user_data`
+/// passed to [`forward_graph_run`].
+/// The arrays are those returned by [`forward_graph_parental_deme_sizes`],
+/// [`forward_graph_offspring_deme_sizes`] and
+/// [`forward_graph_ancestry_proportions_matrix`] and are only
+/// valid during the call.
+/// The offspring sizes and ancestry matrix are NULL in the
+/// last generation of the model.
+///
+/// Returning non-zero stops [`forward_graph_run`].
+pub type ForwardGraphGenerationCallback = Option<
+    unsafe extern "C" fn(
+        time: f64,
+        num_demes: usize,
+        parental_deme_sizes: *const f64,
+        offspring_deme_sizes: *const f64,
+        ancestry_proportions: *const f64,
+        user_data: *mut c_void,
+    ) -> i32,
+>;
+
+/// Walk over every generation of the model, calling `callback`
+/// after updating the state to each time point.
+///
+/// Time points run from `0` to one generation before
+/// [`forward_graph_model_end_time`].
+/// On return, the graph is in the state of the last generation visited.
+///
+/// # Returns
+///
+/// * `0` if every generation was visited.
+/// * `1` if `callback` returned non-zero.
+/// * A negative [`ForwardGraphErrorCode`] on error.
+///
+/// # Safety
+///
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// * `user_data` is passed to `callback` and is not used otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_run(
+    callback: ForwardGraphGenerationCallback,
+    user_data: *mut c_void,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            let callback = match callback {
+                Some(callback) => callback,
+                None => {
+                    return record_error(ForwardGraphErrorCode::NullPointer);
+                }
+            };
+            let num_demes = match &(*graph).graph {
+                Some(fgraph) => fgraph.num_demes_in_model(),
+                None => return record_error(ForwardGraphErrorCode::GraphNotInitialized),
+            };
+            let completed = (*graph).for_each_generation(|handle, time| {
+                // Always set during iteration.
+                let fgraph = handle.graph.as_ref().unwrap();
+                let parental_deme_sizes = match fgraph.parental_deme_sizes() {
+                    Some(slice) => slice.as_ptr() as *const f64,
+                    None => std::ptr::null(),
+                };
+                let offspring_deme_sizes = match fgraph.offspring_deme_sizes() {
+                    Some(slice) => slice.as_ptr() as *const f64,
+                    None => std::ptr::null(),
+                };
+                let ancestry_proportions = if handle.ancestry_proportions.is_empty() {
+                    std::ptr::null()
+                } else {
+                    handle.ancestry_proportions.as_ptr()
+                };
+                callback(
+                    time,
+                    num_demes,
+                    parental_deme_sizes,
+                    offspring_deme_sizes,
+                    ancestry_proportions,
+                    user_data,
+                ) == 0
+            });
+            match completed {
+                Ok(true) => 0,
+                Ok(false) => 1,
+                Err(code) => code as i32,
+            }
+        },
+    )
+}
+
+/// Get the number of generations in the trajectory
+/// filled by [`forward_graph_fill_trajectory`].
+///
+/// This is the number of time points from `0` to one
+/// generation before [`forward_graph_model_end_time`].
+///
+/// # Returns
+///
+/// The number of generations.
+/// Returns `0` and sets `status` to a negative
+/// [`ForwardGraphErrorCode`] on error.
+///
+/// # Safety
+///
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_trajectory_length(
+    graph: *const OpaqueForwardGraph,
+    status: *mut i32,
+) -> usize {
+    ffi_boundary(graph, status, 0, move || {
+        return_if_null!(status, 0);
+        *status = 0;
+        return_if_null!(graph, status, 0);
+        return_if_invalid_handle!(graph, status, 0);
+        match &(*graph).graph {
+            Some(fgraph) => fgraph.end_time().value() as usize,
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                0
+            }
+        }
+    })
+}
+
+// View the f64 newtypes of the model state as f64,
+// as is done when returning pointers to them.
+unsafe fn f64_values<T>(values: Option<&[T]>) -> &[f64] {
+    debug_assert_eq!(std::mem::size_of::<T>(), std::mem::size_of::<f64>());
+    match values {
+        Some(values) => std::slice::from_raw_parts(values.as_ptr() as *const f64, values.len()),
+        None => &[],
+    }
+}
+
+/// Fill caller-provided arrays with the state of
+/// every generation of the model.
+///
+/// Let `G` be [`forward_graph_trajectory_length`] and `N` be
+/// [`forward_graph_number_of_demes`].
+/// `parental_deme_sizes`, `offspring_deme_sizes`, `selfing_rates`
+/// and `cloning_rates` must each have room for `G * N` values.
+/// `ancestry_proportions` must have room for `G * N * N` values.
+/// The values for generation `g` start at `g * N` and `g * N * N`,
+/// respectively, and are laid out as the arrays returned for
+/// a single generation.
+/// In generations without offspring demes, the offspring sizes,
+/// rates and ancestry proportions are `0`.
+///
+/// Any array may be NULL, in which case it is not filled.
+/// On return, the graph is in the state of the last generation.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`] otherwise.
+///
+/// # Safety
+///
+/// * The arrays must be NULL or valid for the number of values above.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_fill_trajectory(
+    parental_deme_sizes: *mut f64,
+    offspring_deme_sizes: *mut f64,
+    selfing_rates: *mut f64,
+    cloning_rates: *mut f64,
+    ancestry_proportions: *mut f64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            let num_demes = match &(*graph).graph {
+                Some(fgraph) => fgraph.num_demes_in_model(),
+                None => return record_error(ForwardGraphErrorCode::GraphNotInitialized),
+            };
+            // Copy `values`, or zeros if there are none,
+            // into row `generation` of `output`.
+            let fill = |output: *mut f64, row_length: usize, generation: usize, values: &[f64]| {
+                if !output.is_null() {
+                    let row = std::slice::from_raw_parts_mut(
+                        output.add(generation * row_length),
+                        row_length,
+                    );
+                    if values.is_empty() {
+                        row.fill(0.0);
+                    } else {
+                        row.copy_from_slice(values);
+                    }
+                }
+            };
+            let mut generation = 0;
+            let completed = (*graph).for_each_generation(|handle, _| {
+                // Always set during iteration.
+                let fgraph = handle.graph.as_ref().unwrap();
+                fill(
+                    parental_deme_sizes,
+                    num_demes,
+                    generation,
+                    f64_values(fgraph.parental_deme_sizes()),
+                );
+                fill(
+                    offspring_deme_sizes,
+                    num_demes,
+                    generation,
+                    f64_values(fgraph.offspring_deme_sizes()),
+                );
+                fill(
+                    selfing_rates,
+                    num_demes,
+                    generation,
+                    f64_values(fgraph.selfing_rates()),
+                );
+                fill(
+                    cloning_rates,
+                    num_demes,
+                    generation,
+                    f64_values(fgraph.cloning_rates()),
+                );
+                fill(
+                    ancestry_proportions,
+                    num_demes * num_demes,
+                    generation,
+                    &handle.ancestry_proportions,
+                );
+                generation += 1;
+                true
+            });
+            match completed {
+                Ok(_) => 0,
+                Err(code) => code as i32,
+            }
+        },
+    )
+}
+
+/// An iterator over the time points of a model.
+///
+/// Unlike [`forward_graph_iterate_time`], any number of
+/// iterators may be used at the same time on one graph.
+/// Allocated by [`forward_graph_time_iterator_allocate`].
+pub struct OpaqueForwardGraphTimeIterator {
+    current_time: f64,
+    // The last time point of the model.
+    last_time: f64,
+}
+
+/// Allocate an iterator over the time points of a model.
+///
+/// Iteration starts at the time set by the last call to
+/// [`forward_graph_update_state`], or at `0` if there has been none,
+/// and ends one generation before [`forward_graph_model_end_time`].
+/// The iterator copies what it needs from `graph` and does not
+/// refer to it afterwards.
+///
+/// # Returns
+///
+/// A new iterator that must be freed by
+/// [`forward_graph_time_iterator_deallocate`].
+/// Returns NULL and sets `status` to a negative
+/// [`ForwardGraphErrorCode`] on error.
+///
+/// # Safety
+///
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_time_iterator_allocate(
+    graph: *const OpaqueForwardGraph,
+    status: *mut i32,
+) -> *mut OpaqueForwardGraphTimeIterator {
+    ffi_boundary(graph, status, std::ptr::null_mut(), move || {
+        return_if_null!(status, std::ptr::null_mut());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null_mut());
+        return_if_invalid_handle!(graph, status, std::ptr::null_mut());
+        match &(*graph).graph {
+            Some(fgraph) => {
+                let current_time = match fgraph.last_time_updated() {
+                    Some(value) => value.value() - 1.0,
+                    None => -1.0,
+                };
+                Box::into_raw(Box::new(OpaqueForwardGraphTimeIterator {
+                    current_time,
+                    last_time: fgraph.end_time().value() - 1.0,
+                }))
+            }
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Advance an iterator to the next time point.
+///
+/// # Returns
+///
+/// `true` and writes the time point to `time` if iteration continues.
+/// Returns `false` when iteration is done or on error,
+/// in which case `status` is set to a negative [`ForwardGraphErrorCode`].
+///
+/// # Safety
+///
+/// * `iterator` must be a valid pointer returned by
+///   [`forward_graph_time_iterator_allocate`].
+/// * `time` must be a valid pointer to an `f64`.
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_time_iterator_next(
+    iterator: *mut OpaqueForwardGraphTimeIterator,
+    time: *mut f64,
+    status: *mut i32,
+) -> bool {
+    ffi_boundary(std::ptr::null(), status, false, move || {
+        return_if_null!(status, false);
+        *status = 0;
+        return_if_null!(iterator, status, false);
+        return_if_null!(time, status, false);
+        let iterator = &mut *iterator;
+        if iterator.current_time < iterator.last_time {
+            iterator.current_time += 1.0;
+            *time = iterator.current_time;
+            true
+        } else {
+            false
+        }
+    })
+}
+
+/// Free an iterator.
+///
+/// Passing NULL is a no-op.
+///
+/// # Safety
+///
+/// `iterator` must be NULL or a pointer returned by
+/// [`forward_graph_time_iterator_allocate`] that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_time_iterator_deallocate(
+    iterator: *mut OpaqueForwardGraphTimeIterator,
+) {
+    catch_panic((), move || {
+        if !iterator.is_null() {
+            let _ = Box::from_raw(iterator);
+        }
+    })
+}
+
+/// # Safety
+///
+/// `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_ancestry_proportions(
+    offspring_deme: usize,
+    status: *mut i32,
+    graph: *mut OpaqueForwardGraph,
+) -> *const f64 {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        if (*graph).error.is_some() {
+            set_status(status, (*graph).error_code);
+            return std::ptr::null();
+        }
+        match &(*graph).graph {
+            Some(fgraph) => {
+                if offspring_deme >= fgraph.num_demes_in_model() {
+                    *status = ForwardGraphErrorCode::IndexOutOfRange as i32;
+                    set_last_error(
+                        ForwardGraphErrorCode::IndexOutOfRange,
+                        error_message_cstring(&format!(
+                            "offspring deme index {} out of range",
+                            offspring_deme
+                        )),
+                    );
+                    std::ptr::null()
+                } else {
+                    match fgraph.ancestry_proportions(offspring_deme) {
+                        Some(proportions) => proportions.as_ptr(),
+                        None => std::ptr::null(),
+                    }
+                }
+            }
+            None => {
+                set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                std::ptr::null()
+            }
+        }
+    })
+}
+
+/// Return a pointer to the first element of the ancestry proportions matrix.
+///
+/// The matrix is stored in row-major order with
+/// [`forward_graph_number_of_demes`] rows and columns.
+/// Row `i` is equal to the array returned by
+/// [`forward_graph_ancestry_proportions`] for offspring deme `i`.
+///
+/// # Returns
+///
+/// NULL if there are no offspring demes.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_ancestry_proportions_matrix(
+    graph: *const OpaqueForwardGraph,
+    status: *mut i32,
+) -> *const f64 {
+    ffi_boundary(graph, status, std::ptr::null(), move || {
+        return_if_null!(status, std::ptr::null());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null());
+        return_if_invalid_handle!(graph, status, std::ptr::null());
+        if (*graph).error.is_some() {
+            set_status(status, (*graph).error_code);
+            return std::ptr::null();
+        }
+        if (*graph).graph.is_none() {
+            set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+            return std::ptr::null();
+        }
+        if (*graph).ancestry_proportions.is_empty() {
+            std::ptr::null()
+        } else {
+            (*graph).ancestry_proportions.as_ptr()
+        }
+    })
+}
+
+// Walker's alias table for sampling an index
+// with given weights from a single uniform number.
+struct AliasTable {
+    probabilities: Vec<f64>,
+    aliases: Vec<usize>,
+}
+
+impl AliasTable {
+    // Returns None if no weight is positive.
+    fn new(weights: &[f64]) -> Option<Self> {
+        let total: f64 = weights.iter().sum();
+        if total.is_nan() || total <= 0.0 {
+            return None;
+        }
+        let n = weights.len();
+        let mut probabilities = weights
+            .iter()
+            .map(|w| w * n as f64 / total)
+            .collect::<Vec<_>>();
+        let mut aliases = (0..n).collect::<Vec<_>>();
+        let (mut small, mut large): (Vec<_>, Vec<_>) =
+            (0..n).partition(|&i| probabilities[i] < 1.0);
+        while let (Some(s), Some(&l)) = (small.pop(), large.last()) {
+            aliases[s] = l;
+            probabilities[l] -= 1.0 - probabilities[s];
+            if probabilities[l] < 1.0 {
+                large.pop();
+                small.push(l);
+            }
+        }
+        // Anything left over is 1 up to rounding error.
+        for i in small.into_iter().chain(large) {
+            probabilities[i] = 1.0;
+        }
+        Some(Self {
+            probabilities,
+            aliases,
+        })
+    }
+
+    // `uniform` must be in [0, 1).
+    fn sample(&self, uniform: f64) -> usize {
+        let x = uniform * self.probabilities.len() as f64;
+        let i = (x as usize).min(self.probabilities.len() - 1);
+        if x - (i as f64) < self.probabilities[i] {
+            i
+        } else {
+            self.aliases[i]
+        }
+    }
+}
+
+/// Samples the parental deme of offspring.
+///
+/// The sampler holds a copy of the ancestry proportions
+/// for the state set by [`forward_graph_update_state`]
+/// and does not refer to the graph afterwards.
+/// Allocated by [`forward_graph_parent_deme_sampler_allocate`].
+pub struct OpaqueParentDemeSampler {
+    // One table per offspring deme,
+    // None if the deme has no ancestry.
+    tables: Vec<Option<AliasTable>>,
+}
+
+impl OpaqueParentDemeSampler {
+    fn new(graph: &OpaqueForwardGraph) -> Result<Self, ForwardGraphErrorCode> {
+        let num_demes = match &graph.graph {
+            Some(fgraph) => fgraph.num_demes_in_model(),
+            None => return Err(ForwardGraphErrorCode::GraphNotInitialized),
+        };
+        let tables = if graph.ancestry_proportions.is_empty() {
+            (0..num_demes).map(|_| None).collect()
+        } else {
+            graph
+                .ancestry_proportions
+                .chunks(num_demes)
+                .map(AliasTable::new)
+                .collect()
+        };
+        Ok(Self { tables })
+    }
+
+    fn sample(&self, offspring_deme: usize, uniform: f64) -> Result<usize, ForwardGraphErrorCode> {
+        match self.tables.get(offspring_deme) {
+            Some(Some(table)) => Ok(table.sample(uniform)),
+            Some(None) => Err(ForwardGraphErrorCode::NoAncestry),
+            None => Err(ForwardGraphErrorCode::IndexOutOfRange),
+        }
+    }
+}
+
+/// Allocate a sampler of parental demes for the
+/// state set by [`forward_graph_update_state`].
+///
+/// # Returns
+///
+/// A new sampler that must be freed by
+/// [`forward_graph_parent_deme_sampler_deallocate`].
+/// Returns NULL and sets `status` to a negative
+/// [`ForwardGraphErrorCode`] on error.
+///
+/// # Safety
+///
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_deme_sampler_allocate(
+    graph: *const OpaqueForwardGraph,
+    status: *mut i32,
+) -> *mut OpaqueParentDemeSampler {
+    ffi_boundary(graph, status, std::ptr::null_mut(), move || {
+        return_if_null!(status, std::ptr::null_mut());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null_mut());
+        return_if_invalid_handle!(graph, status, std::ptr::null_mut());
+        match OpaqueParentDemeSampler::new(&*graph) {
+            Ok(sampler) => Box::into_raw(Box::new(sampler)),
+            Err(code) => {
+                set_status(status, code);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Rebuild a sampler for the current state of `graph`.
+///
+/// This is equivalent to allocating a new sampler
+/// but reuses the memory of `sampler`.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`] otherwise.
+///
+/// # Safety
+///
+/// * `sampler` must be a valid pointer returned by
+///   [`forward_graph_parent_deme_sampler_allocate`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_deme_sampler_update(
+    sampler: *mut OpaqueParentDemeSampler,
+    graph: *const OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(sampler, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            match OpaqueParentDemeSampler::new(&*graph) {
+                Ok(new) => {
+                    *sampler = new;
+                    0
+                }
+                Err(code) => record_error(code),
+            }
+        },
+    )
+}
+
+/// Sample the parental deme of an offspring in `offspring_deme`.
+///
+/// Parental demes are chosen with probabilities
+/// equal to the ancestry proportions of the offspring deme.
+/// `uniform` is a random number uniformly distributed
+/// on `[0, 1)`, supplied by the caller, and is the only
+/// source of randomness.
+///
+/// # Returns
+///
+/// The index of the parental deme.
+/// Returns `-1` and sets `status` to a negative
+/// [`ForwardGraphErrorCode`] if `offspring_deme` is out of range,
+/// if it has no ancestry, or if `uniform` is not in `[0, 1)`.
+///
+/// # Safety
+///
+/// * `sampler` must be a valid pointer returned by
+///   [`forward_graph_parent_deme_sampler_allocate`].
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_deme_sampler_sample(
+    sampler: *const OpaqueParentDemeSampler,
+    offspring_deme: usize,
+    uniform: f64,
+    status: *mut i32,
+) -> isize {
+    ffi_boundary(std::ptr::null(), status, -1, move || {
+        return_if_null!(status, -1);
+        *status = 0;
+        return_if_null!(sampler, status, -1);
+        if !(0.0..1.0).contains(&uniform) {
+            set_status(status, ForwardGraphErrorCode::InvalidArgument);
+            return -1;
+        }
+        match (*sampler).sample(offspring_deme, uniform) {
+            Ok(deme) => deme as isize,
+            Err(code) => {
+                set_status(status, code);
+                -1
+            }
+        }
+    })
+}
+
+/// Sample the parental deme of an offspring in `offspring_deme`,
+/// drawing the random number from `rng`.
+///
+/// See [`forward_graph_parent_deme_sampler_sample`].
+///
+/// # Safety
+///
+/// * `sampler` must be a valid pointer returned by
+///   [`forward_graph_parent_deme_sampler_allocate`].
+/// * `rng` must be a valid pointer to a [`ForwardGraphRng`].
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_deme_sampler_sample_rng(
+    sampler: *const OpaqueParentDemeSampler,
+    offspring_deme: usize,
+    rng: *const ForwardGraphRng,
+    status: *mut i32,
+) -> isize {
+    ffi_boundary(std::ptr::null(), status, -1, move || {
+        return_if_null!(status, -1);
+        *status = 0;
+        return_if_null!(sampler, status, -1);
+        let uniform = match read_rng(rng).and_then(|rng| rng.uniform()) {
+            Ok(uniform) => uniform,
+            Err((code, message)) => {
+                *status = code as i32;
+                set_last_error(code, error_message_cstring(&message));
+                return -1;
+            }
+        };
+        match (*sampler).sample(offspring_deme, uniform) {
+            Ok(deme) => deme as isize,
+            Err(code) => {
+                set_status(status, code);
+                -1
+            }
+        }
+    })
+}
+
+/// Free a sampler.
+///
+/// Passing NULL is a no-op.
+///
+/// # Safety
+///
+/// `sampler` must be NULL or a pointer returned by
+/// [`forward_graph_parent_deme_sampler_allocate`] that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_deme_sampler_deallocate(
+    sampler: *mut OpaqueParentDemeSampler,
+) {
+    catch_panic((), move || {
+        if !sampler.is_null() {
+            let _ = Box::from_raw(sampler);
+        }
+    })
+}
+
+/// How the second parent of an offspring was chosen.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardGraphMating {
+    /// The second parent is a different individual,
+    /// chosen independently of the first.
+    Outcrossing = 0,
+    /// The offspring is produced by selfing and
+    /// the second parent is the first parent.
+    Selfing = 1,
+    /// The offspring is a clone and
+    /// the second parent is the first parent.
+    Cloning = 2,
+}
+
+/// An individual in a parental deme.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ForwardGraphParent {
+    /// The index of the parental deme.
+    pub deme: usize,
+    /// The index of the individual within the parental deme.
+    pub index: u64,
+}
+
+/// The parents of an offspring, as chosen by
+/// [`forward_graph_parent_picker_pick`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ForwardGraphParents {
+    /// The first parent.
+    pub first: ForwardGraphParent,
+    /// The second parent.
+    pub second: ForwardGraphParent,
+    /// How the second parent was chosen.
+    pub mating: ForwardGraphMating,
+}
+
+/// Picks the parents of individual offspring
+/// in a Wright-Fisher model.
+///
+/// The picker holds copies of the ancestry proportions,
+/// the integer parental deme sizes, and the selfing and
+/// cloning rates for the state set by [`forward_graph_update_state`]
+/// and does not refer to the graph afterwards.
+/// Allocated by [`forward_graph_parent_picker_allocate`].
+pub struct OpaqueParentPicker {
+    sampler: OpaqueParentDemeSampler,
+    // The ancestry proportions matrix,
+    // empty if there are no offspring demes.
+    ancestry_proportions: Vec<f64>,
+    parental_sizes: Vec<u64>,
+    // The number of individuals in all parental demes
+    // contributing ancestry to each offspring deme.
+    potential_parents: Vec<u64>,
+    selfing_rates: Vec<f64>,
+    cloning_rates: Vec<f64>,
+}
+
+impl OpaqueParentPicker {
+    unsafe fn new(
+        graph: &OpaqueForwardGraph,
+        rounding: ForwardGraphSizeRounding,
+        rng: *const ForwardGraphRng,
+    ) -> Result<Self, (ForwardGraphErrorCode, String)> {
+        let sampler = OpaqueParentDemeSampler::new(graph)
+            .map_err(|code| (code, code.message().to_string()))?;
+        // Checked by OpaqueParentDemeSampler::new.
+        let fgraph = graph.graph.as_ref().unwrap();
+        let num_demes = fgraph.num_demes_in_model();
+        let parental_sizes = round_deme_sizes::<u64>(
+            f64_values(fgraph.parental_deme_sizes()),
+            num_demes,
+            rounding,
+            rng,
+        )?;
+        let potential_parents = (0..num_demes)
+            .map(|offspring_deme| {
+                graph
+                    .ancestry_proportions
+                    .get(offspring_deme * num_demes..(offspring_deme + 1) * num_demes)
+                    .unwrap_or(&[])
+                    .iter()
+                    .zip(&parental_sizes)
+                    .filter(|(&proportion, _)| proportion > 0.0)
+                    .map(|(_, &size)| size)
+                    .fold(0_u64, u64::saturating_add)
+            })
+            .collect();
+        let rates = |values: &[f64]| {
+            (0..num_demes)
+                .map(|deme| values.get(deme).copied().unwrap_or(0.0))
+                .collect::<Vec<_>>()
+        };
+        Ok(Self {
+            sampler,
+            ancestry_proportions: graph.ancestry_proportions.clone(),
+            parental_sizes,
+            potential_parents,
+            selfing_rates: rates(f64_values(fgraph.selfing_rates())),
+            cloning_rates: rates(f64_values(fgraph.cloning_rates())),
+        })
+    }
+
+    unsafe fn pick_parent(
+        &self,
+        offspring_deme: usize,
+        rng: &ForwardGraphRng,
+    ) -> Result<ForwardGraphParent, (ForwardGraphErrorCode, String)> {
+        let deme = self
+            .sampler
+            .sample(offspring_deme, rng.uniform()?)
+            .map_err(|code| (code, code.message().to_string()))?;
+        let size = self.parental_sizes[deme];
+        if size == 0 {
+            return Err((
+                ForwardGraphErrorCode::NoAncestry,
+                format!("parental deme {} has no individuals", deme),
+            ));
+        }
+        let index = ((rng.uniform()? * size as f64) as u64).min(size - 1);
+        Ok(ForwardGraphParent { deme, index })
+    }
+
+    // Pick a parent other than `first`, with the probabilities of
+    // pick_parent conditional on not picking `first`.
+    // Parental demes are weighted by their ancestry proportion times the
+    // fraction of their individuals that can be picked.
+    unsafe fn pick_other_parent(
+        &self,
+        offspring_deme: usize,
+        first: ForwardGraphParent,
+        rng: &ForwardGraphRng,
+    ) -> Result<ForwardGraphParent, (ForwardGraphErrorCode, String)> {
+        let num_demes = self.parental_sizes.len();
+        let row = &self.ancestry_proportions
+            [offspring_deme * num_demes..(offspring_deme + 1) * num_demes];
+        let weights = row
+            .iter()
+            .zip(&self.parental_sizes)
+            .enumerate()
+            .map(|(deme, (&proportion, &size))| {
+                if deme == first.deme {
+                    proportion * (size - 1) as f64 / size as f64
+                } else {
+                    proportion
+                }
+            })
+            .collect::<Vec<_>>();
+        let total: f64 = weights.iter().sum();
+        if total.is_nan() || total <= 0.0 {
+            return Err((
+                ForwardGraphErrorCode::InvalidArgument,
+                format!(
+                    "offspring deme {} has only one potential parent and cannot outcross",
+                    offspring_deme
+                ),
+            ));
+        }
+        let mut target = rng.uniform()? * total;
+        // Fall back to the last deme with positive weight
+        // in case of rounding error.
+        let mut deme = weights.iter().rposition(|&w| w > 0.0).unwrap_or(0);
+        for (i, &weight) in weights.iter().enumerate() {
+            if weight > 0.0 && target < weight {
+                deme = i;
+                break;
+            }
+            target -= weight;
+        }
+        let size = self.parental_sizes[deme];
+        if size == 0 {
+            return Err((
+                ForwardGraphErrorCode::NoAncestry,
+                format!("parental deme {} has no individuals", deme),
+            ));
+        }
+        let index = if deme == first.deme {
+            // Pick among the other individuals of the deme.
+            let index = ((rng.uniform()? * (size - 1) as f64) as u64).min(size - 2);
+            if index >= first.index {
+                index + 1
+            } else {
+                index
+            }
+        } else {
+            ((rng.uniform()? * size as f64) as u64).min(size - 1)
+        };
+        Ok(ForwardGraphParent { deme, index })
+    }
+
+    unsafe fn pick(
+        &self,
+        offspring_deme: usize,
+        rng: &ForwardGraphRng,
+    ) -> Result<ForwardGraphParents, (ForwardGraphErrorCode, String)> {
+        let first = self.pick_parent(offspring_deme, rng)?;
+        let cloning_rate = self.cloning_rates[offspring_deme];
+        let selfing_rate = self.selfing_rates[offspring_deme];
+        let uniform = rng.uniform()?;
+        let mating = if uniform < cloning_rate {
+            ForwardGraphMating::Cloning
+        } else if uniform < cloning_rate + selfing_rate {
+            ForwardGraphMating::Selfing
+        } else {
+            ForwardGraphMating::Outcrossing
+        };
+        let second = if mating == ForwardGraphMating::Outcrossing {
+            if self.potential_parents[offspring_deme] < 2 {
+                return Err((
+                    ForwardGraphErrorCode::InvalidArgument,
+                    format!(
+                        "offspring deme {} has only one potential parent and cannot outcross",
+                        offspring_deme
+                    ),
+                ));
+            }
+            self.pick_other_parent(offspring_deme, first, rng)?
+        } else {
+            first
+        };
+        Ok(ForwardGraphParents {
+            first,
+            second,
+            mating,
+        })
+    }
+}
+
+/// Allocate a picker of parents for the
+/// state set by [`forward_graph_update_state`].
+///
+/// The parental deme sizes are rounded to integers using `rounding`,
+/// which is a [`ForwardGraphSizeRounding`],
+/// as by [`forward_graph_parental_deme_sizes_u64`].
+/// `rng` is only used, and may otherwise be NULL,
+/// for [`ForwardGraphSizeRounding::Stochastic`].
+///
+/// # Returns
+///
+/// A new picker that must be freed by
+/// [`forward_graph_parent_picker_deallocate`].
+/// Returns NULL and sets `status` to a negative
+/// [`ForwardGraphErrorCode`] on error.
+///
+/// # Safety
+///
+/// * `rng` must be NULL or a valid pointer to a [`ForwardGraphRng`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// * `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_picker_allocate(
+    rounding: i32,
+    rng: *const ForwardGraphRng,
+    graph: *mut OpaqueForwardGraph,
+    status: *mut i32,
+) -> *mut OpaqueParentPicker {
+    ffi_boundary(graph, status, std::ptr::null_mut(), move || {
+        return_if_null!(status, std::ptr::null_mut());
+        *status = 0;
+        return_if_null!(graph, status, std::ptr::null_mut());
+        return_if_invalid_handle!(graph, status, std::ptr::null_mut());
+        match read_enum_argument(rounding, "rounding")
+            .and_then(|rounding| OpaqueParentPicker::new(&*graph, rounding, rng))
+        {
+            Ok(picker) => Box::into_raw(Box::new(picker)),
+            Err(error) => {
+                *status = report_error(error);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Rebuild a picker for the current state of `graph`.
+///
+/// This is equivalent to allocating a new picker
+/// but reuses the memory of `picker`.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`] otherwise.
+///
+/// # Safety
+///
+/// * `picker` must be a valid pointer returned by
+///   [`forward_graph_parent_picker_allocate`].
+/// * `rng` must be NULL or a valid pointer to a [`ForwardGraphRng`].
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_picker_update(
+    picker: *mut OpaqueParentPicker,
+    rounding: i32,
+    rng: *const ForwardGraphRng,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(picker, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            match read_enum_argument(rounding, "rounding")
+                .and_then(|rounding| OpaqueParentPicker::new(&*graph, rounding, rng))
+            {
+                Ok(new) => {
+                    *picker = new;
+                    0
+                }
+                Err(error) => report_error(error),
+            }
+        },
+    )
+}
+
+/// Pick the parents of an offspring in `offspring_deme`.
+///
+/// The first parent is chosen by picking a parental deme with
+/// probabilities equal to the ancestry proportions of the offspring
+/// deme and then an individual uniformly within that deme.
+/// The offspring is then a clone with probability equal to the
+/// cloning rate of `offspring_deme`, is produced by selfing with
+/// probability equal to its selfing rate, and is otherwise produced
+/// by outcrossing.
+/// For cloning and selfing, the second parent is the first parent.
+/// For outcrossing, the second parent is chosen in the same way
+/// as the first, conditional on being a different individual.
+///
+/// All random numbers are drawn from `rng`.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`] otherwise,
+/// in which case `parents` is not written.
+/// It is an error if `offspring_deme` is out of range or has no
+/// ancestry, if the chosen parental deme has no individuals after
+/// rounding, or if an outcrossing offspring has only one potential
+/// parent.
+///
+/// # Safety
+///
+/// * `picker` must be a valid pointer returned by
+///   [`forward_graph_parent_picker_allocate`].
+/// * `rng` must be a valid pointer to a [`ForwardGraphRng`].
+/// * `parents` must be a valid pointer to a [`ForwardGraphParents`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_picker_pick(
+    picker: *const OpaqueParentPicker,
+    offspring_deme: usize,
+    rng: *const ForwardGraphRng,
+    parents: *mut ForwardGraphParents,
+) -> i32 {
+    catch_panic(ForwardGraphErrorCode::Panic as i32, move || {
+        return_if_null!(picker, ForwardGraphErrorCode::NullPointer as i32);
+        return_if_null!(parents, ForwardGraphErrorCode::NullPointer as i32);
+        match read_rng(rng).and_then(|rng| (*picker).pick(offspring_deme, &rng)) {
+            Ok(picked) => {
+                *parents = picked;
+                0
+            }
+            Err((code, message)) => {
+                set_last_error(code, error_message_cstring(&message));
+                code as i32
+            }
+        }
+    })
+}
+
+/// Free a picker.
+///
+/// Passing NULL is a no-op.
+///
+/// # Safety
+///
+/// `picker` must be NULL or a pointer returned by
+/// [`forward_graph_parent_picker_allocate`] that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_parent_picker_deallocate(picker: *mut OpaqueParentPicker) {
+    catch_panic((), move || {
+        if !picker.is_null() {
+            let _ = Box::from_raw(picker);
+        }
+    })
+}
+
+/// How offspring are allocated to parental demes by
+/// [`forward_graph_offspring_allocation`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardGraphAllocationMethod {
+    /// Draw the counts from a multinomial distribution
+    /// with the ancestry proportions as probabilities.
+    /// The counts are drawn as a sequence of binomials,
+    /// with one random number per parental deme.
+    Multinomial = 0,
+    /// Apportion the counts deterministically by the largest
+    /// remainder method: each parental deme gets the integer part
+    /// of its share, and the remaining offspring go to the demes
+    /// with the largest fractional parts, with ties going to
+    /// the deme with the lower index.
+    LargestRemainder = 1,
+}
+
+impl_try_from_i32!(ForwardGraphAllocationMethod, Multinomial, LargestRemainder);
+
+fn largest_remainder(n: u64, proportions: &[f64]) -> Vec<u64> {
+    let total: f64 = proportions.iter().sum();
+    let quotas = proportions
+        .iter()
+        .map(|p| n as f64 * p / total)
+        .collect::<Vec<_>>();
+    let mut counts = quotas.iter().map(|q| q.floor() as u64).collect::<Vec<_>>();
+    let mut order = (0..proportions.len())
+        .filter(|&i| proportions[i] > 0.0)
+        .collect::<Vec<_>>();
+    order.sort_by(|&i, &j| {
+        let fi = quotas[i] - quotas[i].floor();
+        let fj = quotas[j] - quotas[j].floor();
+        fj.total_cmp(&fi).then(i.cmp(&j))
+    });
+    // n is not exact as an f64 when it is large,
+    // so the floored quotas may sum to more than n.
+    // The excess is taken back from the smallest fractional parts.
+    let assigned = counts.iter().map(|&c| u128::from(c)).sum::<u128>();
+    match u128::from(n).checked_sub(assigned) {
+        Some(remaining) => {
+            for &i in order.iter().cycle().take(remaining as usize) {
+                counts[i] += 1;
+            }
+        }
+        None => {
+            let mut excess = assigned - u128::from(n);
+            for &i in order.iter().rev().cycle() {
+                if excess == 0 {
+                    break;
+                }
+                if counts[i] > 0 {
+                    counts[i] -= 1;
+                    excess -= 1;
+                }
+            }
+        }
+    }
+    debug_assert_eq!(counts.iter().sum::<u64>(), n);
+    counts
+}
+
+// ln(k!), using Stirling's series for large k.
+fn ln_factorial(k: u64) -> f64 {
+    if k < 20 {
+        (2..=k).map(|i| (i as f64).ln()).sum()
+    } else {
+        let x = k as f64;
+        let x2 = x * x;
+        x * x.ln() - x + 0.5 * (2.0 * std::f64::consts::PI * x).ln() + 1.0 / (12.0 * x)
+            - 1.0 / (360.0 * x * x2)
+            + 1.0 / (1260.0 * x * x2 * x2)
+    }
+}
+
+// Draw from a binomial distribution by inversion from a single
+// uniform number, visiting outcomes outwards from the mode.
+// The cost grows with the standard deviation rather than with `n`.
+fn binomial(n: u64, p: f64, uniform: f64) -> u64 {
+    if n == 0 || p <= 0.0 {
+        return 0;
+    }
+    if p >= 1.0 {
+        return n;
+    }
+    let q = 1.0 - p;
+    let mode = (((n + 1) as f64 * p).floor() as u64).min(n);
+    let pmf_mode = (ln_factorial(n) - ln_factorial(mode) - ln_factorial(n - mode)
+        + mode as f64 * p.ln()
+        + (n - mode) as f64 * q.ln())
+    .exp();
+    let ratio = p / q;
+    let mut u = uniform;
+    if u < pmf_mode {
+        return mode;
+    }
+    u -= pmf_mode;
+    let (mut lower, mut pmf_lower) = (mode, pmf_mode);
+    let (mut upper, mut pmf_upper) = (mode, pmf_mode);
+    loop {
+        let mut moved = false;
+        if lower > 0 && pmf_lower > 0.0 {
+            pmf_lower *= lower as f64 / ((n - lower + 1) as f64 * ratio);
+            lower -= 1;
+            moved = true;
+            if u < pmf_lower {
+                return lower;
+            }
+            u -= pmf_lower;
+        }
+        if upper < n && pmf_upper > 0.0 {
+            pmf_upper *= (n - upper) as f64 / (upper + 1) as f64 * ratio;
+            upper += 1;
+            moved = true;
+            if u < pmf_upper {
+                return upper;
+            }
+            u -= pmf_upper;
+        }
+        // The remaining probability is lost to rounding error.
+        if !moved {
+            return mode;
+        }
+    }
+}
+
+// Allocate the offspring of each deme to parental demes,
+// returning the row-major matrix of counts.
+// The counts are multinomial if `rng` is given, drawn as a
+// sequence of binomials with one random number per parental deme,
+// and are apportioned by the largest remainder method otherwise.
+unsafe fn allocate_offspring(
+    sizes: &[u64],
+    proportions: &[f64],
+    rng: Option<&ForwardGraphRng>,
+) -> Result<Vec<u64>, (ForwardGraphErrorCode, String)> {
+    let num_demes = sizes.len();
+    let mut counts = vec![0; num_demes * num_demes];
+    for (offspring_deme, &size) in sizes.iter().enumerate() {
+        if size == 0 {
+            continue;
+        }
+        let row = proportions
+            .get(offspring_deme * num_demes..(offspring_deme + 1) * num_demes)
+            .filter(|row| row.iter().any(|&p| p > 0.0))
+            .ok_or_else(|| {
+                (
+                    ForwardGraphErrorCode::NoAncestry,
+                    format!("offspring deme {} has no ancestry", offspring_deme),
+                )
+            })?;
+        let output = &mut counts[offspring_deme * num_demes..(offspring_deme + 1) * num_demes];
+        match rng {
+            Some(rng) => {
+                // Every remaining offspring goes
+                // to the last deme with ancestry.
+                let last = row.iter().rposition(|&p| p > 0.0).unwrap_or(0);
+                let mut remaining = size;
+                let mut remaining_proportion: f64 = row.iter().sum();
+                for (parental_deme, &proportion) in row.iter().enumerate() {
+                    if remaining == 0 || parental_deme == last {
+                        output[parental_deme] = remaining;
+                        break;
+                    }
+                    if proportion > 0.0 {
+                        let p = (proportion / remaining_proportion).min(1.0);
+                        let count = binomial(remaining, p, rng.uniform()?);
+                        output[parental_deme] = count;
+                        remaining -= count;
+                        remaining_proportion -= proportion;
+                    }
+                }
+            }
+            None => output.copy_from_slice(&largest_remainder(size, row)),
+        }
+    }
+    Ok(counts)
+}
+
+/// Allocate the offspring of each deme to parental demes
+/// for the state set by [`forward_graph_update_state`].
+///
+/// The offspring deme sizes are rounded to integers using `rounding`,
+/// which is a [`ForwardGraphSizeRounding`],
+/// as by [`forward_graph_offspring_deme_sizes_u64`], and are then
+/// divided among parental demes according to the ancestry proportions
+/// using `method`, which is a [`ForwardGraphAllocationMethod`].
+/// The result is written to `counts`, which is a row-major matrix with
+/// [`forward_graph_number_of_demes`] rows and columns.
+/// Rows are offspring demes and columns are parental demes.
+/// Each row sums to the integer size of the offspring deme.
+/// All counts are `0` if there are no offspring demes.
+///
+/// `rng` is used for [`ForwardGraphAllocationMethod::Multinomial`] and
+/// [`ForwardGraphSizeRounding::Stochastic`].
+/// It is not used, and may be NULL, otherwise.
+///
+/// # Returns
+///
+/// `0` on success and a negative [`ForwardGraphErrorCode`] otherwise.
+///
+/// # Safety
+///
+/// * `rng` must be NULL or a valid pointer to a [`ForwardGraphRng`].
+/// * `counts` must be valid for the square of
+///   [`forward_graph_number_of_demes`] values.
+/// * `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_offspring_allocation(
+    method: i32,
+    rounding: i32,
+    rng: *const ForwardGraphRng,
+    counts: *mut u64,
+    graph: *mut OpaqueForwardGraph,
+) -> i32 {
+    ffi_boundary(
+        graph,
+        std::ptr::null_mut(),
+        ForwardGraphErrorCode::Panic as i32,
+        move || {
+            return_if_null!(graph, ForwardGraphErrorCode::NullPointer as i32);
+            return_if_invalid_handle!(graph, ForwardGraphErrorCode::InvalidHandle as i32);
+            return_if_null!(counts, ForwardGraphErrorCode::NullPointer as i32);
+            let method: ForwardGraphAllocationMethod = match read_enum_argument(method, "method") {
+                Ok(method) => method,
+                Err(error) => return report_error(error),
+            };
+            let rounding = match read_enum_argument(rounding, "rounding") {
+                Ok(rounding) => rounding,
+                Err(error) => return report_error(error),
+            };
+            let fgraph = match &(*graph).graph {
+                Some(fgraph) => fgraph,
+                None => return record_error(ForwardGraphErrorCode::GraphNotInitialized),
+            };
+            let num_demes = fgraph.num_demes_in_model();
+            let sizes = match round_deme_sizes::<u64>(
+                f64_values(fgraph.offspring_deme_sizes()),
+                num_demes,
+                rounding,
+                rng,
+            ) {
+                Ok(sizes) => sizes,
+                Err(error) => return report_error(error),
+            };
+            let rng = if method == ForwardGraphAllocationMethod::Multinomial {
+                match read_rng(rng) {
+                    Ok(rng) => Some(rng),
+                    Err(error) => return report_error(error),
+                }
+            } else {
+                None
+            };
+            // Only write to `counts` once every row
+            // has been filled without error.
+            match allocate_offspring(&sizes, &(*graph).ancestry_proportions, rng.as_ref()) {
+                Ok(rv) => {
+                    std::slice::from_raw_parts_mut(counts, num_demes * num_demes)
+                        .copy_from_slice(&rv);
+                }
+                Err(error) => return report_error(error),
+            }
+            0
+        },
+    )
+}
+
+/// Get the model end time.
+///
+/// The value returned is one generation after the
+/// last parental generation.
+/// Thus, this value defines a half-open interval
+/// during which parental demes exist.
+///
+/// # Safety
+///
+/// `graph` must be a valid pointer to an [`OpaqueForwardGraph`].
+/// `status` must be a valid pointer to an `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn forward_graph_model_end_time(
+    status: *mut i32,
+    graph: *const OpaqueForwardGraph,
+) -> f64 {
+    ffi_boundary(graph, status, f64::NAN, move || {
+        return_if_null!(status, f64::NAN);
+        *status = 0;
+        return_if_null!(graph, status, f64::NAN);
+        return_if_invalid_handle!(graph, status, f64::NAN);
+        if (*graph).error.is_some() {
+            set_status(status, (*graph).error_code);
+            f64::NAN
+        } else {
+            match &(*graph).graph {
+                Some(fgraph) => fgraph.end_time().value(),
+                None => {
+                    set_status(status, ForwardGraphErrorCode::GraphNotInitialized);
+                    f64::NAN
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::CString, io::Write};
+
+    struct GraphHolder {
+        graph: *mut OpaqueForwardGraph,
+    }
+
+    impl GraphHolder {
+        fn new() -> Self {
+            Self {
+                graph: forward_graph_allocate(),
+            }
+        }
+
+        fn as_mut_ptr(&mut self) -> *mut OpaqueForwardGraph {
+            self.graph
+        }
+
+        fn as_ptr(&mut self) -> *const OpaqueForwardGraph {
+            self.graph
+        }
+
+        fn init_with_yaml(&mut self, burnin: f64, yaml: &str) -> i32 {
+            let yaml_cstr = CString::new(yaml).unwrap();
+            let yaml_c_char: *const c_char = yaml_cstr.as_ptr() as *const c_char;
+            unsafe { forward_graph_initialize_from_yaml(yaml_c_char, burnin, self.as_mut_ptr()) }
+        }
+    }
+
+    impl Drop for GraphHolder {
+        fn drop(&mut self) {
+            unsafe { forward_graph_deallocate(self.as_mut_ptr()) };
+        }
+    }
+
+    #[test]
+    fn test_invalid_graph() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   start_time: 55
+   epochs:
+   - start_size: 100
+     end_time: 50
+   - start_size: 200
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(
+            graph.init_with_yaml(100.0, yaml),
+            ForwardGraphErrorCode::DemesError as i32
+        );
+        assert!(unsafe { forward_graph_is_error_state(graph.as_ptr()) });
+        assert_eq!(
+            unsafe { forward_graph_get_error_code(graph.as_ptr()) },
+            ForwardGraphErrorCode::DemesError
+        );
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        let message = unsafe { forward_graph_get_error_message(graph.as_ptr(), pstatus) };
+        assert_eq!(status, 0);
+        assert!(!message.is_null());
+        let rust_message = unsafe { CStr::from_ptr(message) };
+        let rust_message: &str = rust_message.to_str().unwrap();
+        assert_eq!(
+            rust_message,
+            "\"deme A has finite start time but no ancestors\""
+        );
+        let length = unsafe { forward_graph_get_error_message_length(graph.as_ptr(), pstatus) };
+        assert_eq!(status, 0);
+        assert_eq!(length, rust_message.len());
+    }
+
+    #[test]
+    fn test_non_ascii_error_message() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: Açores
+   start_time: 55
+   epochs:
+   - start_size: 100
+";
+        let mut graph = GraphHolder::new();
+        graph.init_with_yaml(100.0, yaml);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        let message = unsafe { forward_graph_get_error_message(graph.as_ptr(), pstatus) };
+        assert_eq!(status, 0);
+        let rust_message = unsafe { CStr::from_ptr(message) }.to_str().unwrap();
+        assert_eq!(
+            rust_message,
+            "\"deme Açores has finite start time but no ancestors\""
+        );
+        let length = unsafe { forward_graph_get_error_message_length(graph.as_ptr(), pstatus) };
+        assert_eq!(length, rust_message.len());
+        assert!(length > rust_message.chars().count());
+    }
+
+    #[test]
+    fn test_error_message_with_interior_nul() {
+        let mut graph = GraphHolder::new();
+        unsafe {
+            (*graph.as_mut_ptr()).update_error(Some((
+                ForwardGraphErrorCode::InternalError,
+                "a\0b".to_string(),
+            )))
+        };
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        let message = unsafe { forward_graph_get_error_message(graph.as_ptr(), pstatus) };
+        assert_eq!(unsafe { CStr::from_ptr(message) }.to_str().unwrap(), "ab");
+    }
+
+    #[test]
+    fn test_empty_graph() {
+        let yaml = "";
+        let mut graph = GraphHolder::new();
+        graph.init_with_yaml(100.0, yaml);
+        assert!(unsafe { forward_graph_is_error_state(graph.as_ptr()) });
+    }
+
+    #[test]
+    fn test_null_graph() {
+        let yaml: *const c_char = std::ptr::null();
+        let graph = forward_graph_allocate();
+        assert_eq!(
+            unsafe { forward_graph_initialize_from_yaml(yaml, 100.0, graph) },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+        assert!(unsafe { forward_graph_is_error_state(graph) });
+        unsafe { forward_graph_deallocate(graph) };
+    }
+
+    #[test]
+    fn number_of_demes_in_model() {
+        {
+            let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 50
+   - start_size: 200
+";
+            let mut graph = GraphHolder::new();
+            graph.init_with_yaml(100.0, yaml);
+            let num_demes = unsafe { forward_graph_number_of_demes(graph.as_ptr()) };
+            assert_eq!(num_demes, 1);
+        }
+    }
+
+    #[test]
+    fn test_deme_names_and_indexes() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 50
+ - name: B
+   ancestors: [A]
+   epochs:
+   - start_size: 200
+";
+        let mut graph = GraphHolder::new();
+        graph.init_with_yaml(100.0, yaml);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        for (i, expected) in ["A", "B"].iter().enumerate() {
+            let name = unsafe { forward_graph_deme_name(i, pstatus, graph.as_mut_ptr()) };
+            assert_eq!(status, 0);
+            assert!(!name.is_null());
+            let name = unsafe { CStr::from_ptr(name) };
+            assert_eq!(&name.to_str().unwrap(), expected);
+
+            let name = CString::new(*expected).unwrap();
+            status = -1;
+            let index =
+                unsafe { forward_graph_deme_index(name.as_ptr(), pstatus, graph.as_mut_ptr()) };
+            assert_eq!(status, 0);
+            assert_eq!(index, i as isize);
+        }
+
+        let name = unsafe { forward_graph_deme_name(2, pstatus, graph.as_mut_ptr()) };
+        assert_eq!(status, ForwardGraphErrorCode::IndexOutOfRange as i32);
+        assert!(name.is_null());
+
+        let name = CString::new("C").unwrap();
+        let index = unsafe { forward_graph_deme_index(name.as_ptr(), pstatus, graph.as_mut_ptr()) };
+        assert_eq!(status, ForwardGraphErrorCode::UnknownDeme as i32);
+        assert_eq!(index, -1);
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::UnknownDeme
+        );
+        // A failed lookup does not change the state of the graph.
+        assert_eq!(
+            unsafe { forward_graph_get_error_code(graph.as_ptr()) },
+            ForwardGraphErrorCode::NoError
+        );
+        assert_eq!(
+            unsafe { forward_graph_update_state(0.0, graph.as_mut_ptr()) },
+            0
+        );
+        assert!(
+            !unsafe { forward_graph_ancestry_proportions_matrix(graph.as_ptr(), pstatus) }
+                .is_null()
+        );
+        assert_eq!(status, 0);
+        assert_eq!(
+            unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+            151.0
+        );
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+";
+        let mut graph = GraphHolder::new();
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert!(unsafe { forward_graph_parental_deme_sizes(graph.as_ptr(), pstatus) }.is_null());
+        assert_eq!(status, ForwardGraphErrorCode::GraphNotInitialized as i32);
+        assert_eq!(
+            unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) },
+            std::ptr::null()
+        );
+        assert_eq!(
+            status,
+            ForwardGraphErrorCode::IterationNotInitialized as i32
+        );
+
+        assert_eq!(graph.init_with_yaml(100.0, yaml), 0);
+        assert_eq!(
+            unsafe { forward_graph_get_error_code(graph.as_ptr()) },
+            ForwardGraphErrorCode::NoError
+        );
+        assert!(
+            unsafe { forward_graph_ancestry_proportions(1, pstatus, graph.as_mut_ptr()) }.is_null()
+        );
+        assert_eq!(status, ForwardGraphErrorCode::IndexOutOfRange as i32);
+        assert_eq!(
+            unsafe { forward_graph_get_error_code(graph.as_ptr()) },
+            ForwardGraphErrorCode::NoError
+        );
+        assert!(!unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) }.is_nan());
+        assert_eq!(status, 0);
+
+        assert_eq!(
+            unsafe { forward_graph_update_state(-1.0, graph.as_mut_ptr()) },
+            ForwardGraphErrorCode::TimeError as i32
+        );
+        assert_eq!(
+            unsafe { forward_graph_get_error_code(graph.as_ptr()) },
+            ForwardGraphErrorCode::TimeError
+        );
+
+        assert_eq!(
+            graph.init_with_yaml(100.0, "demes: ["),
+            ForwardGraphErrorCode::YamlError as i32
+        );
+    }
+
+    #[test]
+    fn test_epochs() {
+        let yaml = "
+time_units: years
+generation_time: 25
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 1250
+   - start_size: 200
+     end_size: 400
+     size_function: linear
+     selfing_rate: 0.5
+ - name: B
+   ancestors: [A]
+   start_time: 500
+   epochs:
+   - start_size: 50
+     cloning_rate: 0.25
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(100.0, yaml), 0);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert_eq!(
+            unsafe { forward_graph_number_of_epochs(0, pstatus, graph.as_mut_ptr()) },
+            2
+        );
+        assert_eq!(status, 0);
+        assert_eq!(
+            unsafe { forward_graph_number_of_epochs(1, pstatus, graph.as_mut_ptr()) },
+            1
+        );
+        assert_eq!(
+            unsafe { forward_graph_number_of_epochs(2, pstatus, graph.as_mut_ptr()) },
+            -1
+        );
+        assert_eq!(status, ForwardGraphErrorCode::IndexOutOfRange as i32);
+
+        let mut epoch = std::mem::MaybeUninit::<ForwardGraphEpoch>::uninit();
+        assert_eq!(
+            unsafe { forward_graph_epoch(0, 0, epoch.as_mut_ptr(), graph.as_mut_ptr()) },
+            0
+        );
+        let epoch0 = unsafe { epoch.assume_init() };
+        assert!(epoch0.start_time.is_infinite());
+        assert_eq!(epoch0.end_time, 50.0);
+        assert_eq!(epoch0.start_size, 100.0);
+        assert_eq!(epoch0.size_function, ForwardGraphSizeFunction::Constant);
+
+        assert_eq!(
+            unsafe { forward_graph_epoch(0, 1, epoch.as_mut_ptr(), graph.as_mut_ptr()) },
+            0
+        );
+        let epoch1 = unsafe { epoch.assume_init() };
+        assert_eq!(epoch1.start_time, 50.0);
+        assert_eq!(epoch1.end_time, 0.0);
+        assert_eq!(epoch1.start_size, 200.0);
+        assert_eq!(epoch1.end_size, 400.0);
+        assert_eq!(epoch1.size_function, ForwardGraphSizeFunction::Linear);
+        assert_eq!(epoch1.selfing_rate, 0.5);
+        assert_eq!(epoch1.cloning_rate, 0.0);
+
+        assert_eq!(
+            unsafe { forward_graph_epoch(1, 0, epoch.as_mut_ptr(), graph.as_mut_ptr()) },
+            0
+        );
+        let epoch = unsafe { epoch.assume_init() };
+        assert_eq!(epoch.start_time, 20.0);
+        assert_eq!(epoch.cloning_rate, 0.25);
+
+        let mut epoch = std::mem::MaybeUninit::<ForwardGraphEpoch>::uninit();
+        assert_eq!(
+            unsafe { forward_graph_epoch(1, 1, epoch.as_mut_ptr(), graph.as_mut_ptr()) },
+            ForwardGraphErrorCode::IndexOutOfRange as i32
+        );
+        assert!(!unsafe { forward_graph_is_error_state(graph.as_ptr()) });
+    }
+
+    #[test]
+    fn test_migration_matrix() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+ - name: B
+   epochs:
+   - start_size: 100
+migrations:
+ - source: A
+   dest: B
+   rate: 0.1
+   start_time: 50
+   end_time: 10
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert!(unsafe { forward_graph_migration_matrix(graph.as_ptr(), pstatus) }.is_null());
+        assert_eq!(status, 0);
+
+        // The model ends at 61, so
+        // time 20 is 40 generations ago
+        assert_eq!(
+            unsafe { forward_graph_update_state(20.0, graph.as_mut_ptr()) },
+            0
+        );
+        let matrix = unsafe { forward_graph_migration_matrix(graph.as_ptr(), pstatus) };
+        assert_eq!(status, 0);
+        assert!(!matrix.is_null());
+        let matrix = unsafe { std::slice::from_raw_parts(matrix, 4) };
+        assert_eq!(matrix, &[0.0, 0.0, 0.1, 0.0]);
+        let ancestry =
+            unsafe { forward_graph_ancestry_proportions(1, pstatus, graph.as_mut_ptr()) };
+        let ancestry = unsafe { std::slice::from_raw_parts(ancestry, 2) };
+        assert!((ancestry[0] - 0.1).abs() <= 1e-9);
+        assert!((ancestry[1] - 0.9).abs() <= 1e-9);
+
+        // time 50 is the migration end time.
+        assert_eq!(
+            unsafe { forward_graph_update_state(50.0, graph.as_mut_ptr()) },
+            0
+        );
+        let matrix = unsafe { forward_graph_migration_matrix(graph.as_ptr(), pstatus) };
+        let matrix = unsafe { std::slice::from_raw_parts(matrix, 4) };
+        assert_eq!(matrix, &[0.0; 4]);
+
+        assert_eq!(
+            unsafe { forward_graph_update_state(60.0, graph.as_mut_ptr()) },
+            0
+        );
+        assert!(unsafe { forward_graph_migration_matrix(graph.as_ptr(), pstatus) }.is_null());
+    }
+
+    #[test]
+    fn test_pulses() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+ - name: B
+   epochs:
+   - start_size: 100
+pulses:
+ - sources: [A]
+   dest: B
+   proportions: [0.25]
+   time: 20
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        assert_eq!(unsafe { forward_graph_number_of_pulses(graph.as_ptr()) }, 1);
+
+        let mut pulse = std::mem::MaybeUninit::<ForwardGraphPulse>::uninit();
+        assert_eq!(
+            unsafe { forward_graph_pulse(0, pulse.as_mut_ptr(), graph.as_mut_ptr()) },
+            0
+        );
+        let pulse = unsafe { pulse.assume_init() };
+        // The model ends at 31, so the pulse
+        // is 20 generations before the last parental generation.
+        assert_eq!(pulse.time, 10.0);
+        assert_eq!(pulse.dest, 1);
+        assert_eq!(pulse.num_sources, 1);
+        assert_eq!(unsafe { *pulse.sources }, 0);
+        assert_eq!(unsafe { *pulse.proportions }, 0.25);
+
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        let mut num_pulses = 1;
+        for time in [9.0, 10.0, 11.0] {
+            assert_eq!(
+                unsafe { forward_graph_update_state(time, graph.as_mut_ptr()) },
+                0
+            );
+            let pulses =
+                unsafe { forward_graph_current_pulses(graph.as_ptr(), &mut num_pulses, pstatus) };
+            assert_eq!(status, 0);
+            if time == 10.0 {
+                assert_eq!(num_pulses, 1);
+                assert_eq!(unsafe { *pulses }, 0);
+                let ancestry =
+                    unsafe { forward_graph_ancestry_proportions(1, pstatus, graph.as_mut_ptr()) };
+                let ancestry = unsafe { std::slice::from_raw_parts(ancestry, 2) };
+                assert!((ancestry[0] - 0.25).abs() <= 1e-9);
+            } else {
+                assert_eq!(num_pulses, 0);
+                assert!(pulses.is_null());
+            }
+        }
+
+        let mut pulse = std::mem::MaybeUninit::<ForwardGraphPulse>::uninit();
+        assert_eq!(
+            unsafe { forward_graph_pulse(1, pulse.as_mut_ptr(), graph.as_mut_ptr()) },
+            ForwardGraphErrorCode::IndexOutOfRange as i32
+        );
+        assert!(!unsafe { forward_graph_is_error_state(graph.as_ptr()) });
+    }
+
+    #[test]
+    fn test_ancestry_proportions_matrix() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+ - name: B
+   epochs:
+   - start_size: 100
+ - name: C
+   ancestors: [A, B]
+   proportions: [0.5, 0.5]
+   start_time: 10
+   epochs:
+   - start_size: 100
+migrations:
+ - source: A
+   dest: B
+   rate: 0.1
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        let end_time = unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) };
+        let mut time = 0.0;
+        while time < end_time {
+            assert_eq!(
+                unsafe { forward_graph_update_state(time, graph.as_mut_ptr()) },
+                0
+            );
+            let matrix =
+                unsafe { forward_graph_ancestry_proportions_matrix(graph.as_ptr(), pstatus) };
+            assert_eq!(status, 0);
+            if time < end_time - 1.0 {
+                let matrix = unsafe { std::slice::from_raw_parts(matrix, 9) };
+                for (offspring_deme, row) in matrix.chunks(3).enumerate() {
+                    let proportions = unsafe {
+                        forward_graph_ancestry_proportions(
+                            offspring_deme,
+                            pstatus,
+                            graph.as_mut_ptr(),
+                        )
+                    };
+                    let proportions = unsafe { std::slice::from_raw_parts(proportions, 3) };
+                    assert_eq!(row, proportions);
+                }
+            } else {
+                assert!(matrix.is_null());
+            }
+            time += 1.0;
+        }
+    }
+
+    #[test]
+    fn iterate_simple_model() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 50
+   - start_size: 200
+";
+        let mut graph = GraphHolder::new();
+        graph.init_with_yaml(100.0, yaml);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert!(unsafe { forward_graph_selfing_rates(graph.as_ptr(), pstatus) }.is_null());
+        assert_eq!(status, 0);
+        status = -1;
+        assert!(unsafe { forward_graph_cloning_rates(graph.as_ptr(), pstatus) }.is_null());
+        assert_eq!(status, 0);
+        status = -1;
+        assert!(unsafe { forward_graph_parental_deme_sizes(graph.as_ptr(), pstatus) }.is_null(),);
+        assert_eq!(status, 0);
+        status = -1;
+        assert!(unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) }.is_null(),);
+        assert_eq!(status, 0);
+        status = -1;
+        assert!(!unsafe { forward_graph_any_extant_offspring_demes(graph.as_ptr(), pstatus) });
+        assert_eq!(status, 0);
+        status = -1;
+        assert!(!unsafe { forward_graph_any_extant_parent_demes(graph.as_ptr(), pstatus) });
+        assert_eq!(status, 0);
+
+        {
+            assert_eq!(
+                unsafe { forward_graph_initialize_time_iteration(graph.as_mut_ptr()) },
+                0,
+            );
+            let mut ngens = -1_i32;
+            let mut ptime: *const f64;
+            let mut ancestry_proportions: *const f64;
+            let mut times = vec![];
+            let mut sizes = vec![100.0; 100];
+            sizes.append(&mut vec![200.0; 50]);
+
+            let mut status = -1;
+            let pstatus: *mut i32 = &mut status;
+            ptime = unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) };
+            assert_eq!(
+                unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+                151.0
+            );
+            assert_eq!(status, 0);
+            while !ptime.is_null() {
+                assert_eq!(status, 0);
+                ngens += 1;
+                unsafe { times.push(*ptime) };
+                assert_eq!(
+                    unsafe { forward_graph_update_state(*ptime, graph.as_mut_ptr()) },
+                    0,
+                );
+                let mut status = -1;
+                let pstatus: *mut i32 = &mut status;
+                if unsafe { forward_graph_any_extant_offspring_demes(graph.as_ptr(), pstatus) } {
+                    assert_eq!(status, 0);
+                    let offspring_deme_sizes =
+                        unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
+                    assert_eq!(status, 0);
+                    assert!(!offspring_deme_sizes.is_null());
+                    ancestry_proportions = unsafe {
+                        forward_graph_ancestry_proportions(0, pstatus, graph.as_mut_ptr())
+                    };
+                    assert_eq!(status, 0);
+                    let ancestry_proportions =
+                        unsafe { std::slice::from_raw_parts(ancestry_proportions, 1) };
+                    assert!((ancestry_proportions[0] - 1.0) <= 1e-9);
+                    let deme_sizes = unsafe { std::slice::from_raw_parts(offspring_deme_sizes, 1) };
+                    assert_eq!(deme_sizes[0], sizes[ngens as usize]);
+                } else {
+                    status = -1;
+                    let offspring_deme_sizes =
+                        unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
+                    assert_eq!(status, 0);
+                    assert!(offspring_deme_sizes.is_null());
+                }
+                ptime = unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) };
+            }
+            assert!(ptime.is_null());
+            assert_eq!(times.first().unwrap(), &0.0);
+            assert_eq!(times.last().unwrap(), &150.0);
+            assert_eq!(ngens, 150);
+        }
+
+        // Now, start from time of 50
+        {
+            assert_eq!(
+                unsafe { forward_graph_update_state(50.0, graph.as_mut_ptr()) },
+                0,
+            );
+            assert_eq!(
+                unsafe { forward_graph_initialize_time_iteration(graph.as_mut_ptr()) },
+                0,
+            );
+            let mut ngens = -1_i32;
+            let mut ptime: *const f64;
+            let mut times = vec![];
+            let mut sizes = vec![100.0; 50];
+            sizes.append(&mut vec![200.0; 50]);
+
+            let mut status = -1;
+            let pstatus: *mut i32 = &mut status;
+            ptime = unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) };
+            while !ptime.is_null() {
+                assert_eq!(status, 0);
+                ngens += 1;
+                unsafe { times.push(*ptime) };
+                assert_eq!(
+                    unsafe { forward_graph_update_state(*ptime, graph.as_mut_ptr()) },
+                    0,
+                );
+                let mut status = -1;
+                let pstatus: *mut i32 = &mut status;
+                if unsafe { forward_graph_any_extant_offspring_demes(graph.as_ptr(), pstatus) } {
+                    assert_eq!(status, 0);
+                    let offspring_deme_sizes =
+                        unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
+                    assert_eq!(status, 0);
+                    assert!(!offspring_deme_sizes.is_null());
+                    let deme_sizes = unsafe { std::slice::from_raw_parts(offspring_deme_sizes, 1) };
+                    assert_eq!(deme_sizes[0], sizes[ngens as usize]);
+                } else {
+                    status = -1;
+                    let offspring_deme_sizes =
+                        unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
+                    assert_eq!(status, 0);
+                    assert!(offspring_deme_sizes.is_null());
+                }
+                ptime = unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) };
+            }
+            assert!(ptime.is_null());
+            assert_eq!(times.first().unwrap(), &50.0);
+            assert_eq!(times.last().unwrap(), &150.0);
+            assert_eq!(ngens, 100);
+        }
+    }
+
+    #[test]
+    fn test_from_yaml_file() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 50
+   - start_size: 200
+";
+        {
+            let mut file = std::fs::File::create("simple_model.yaml").unwrap();
+            file.write_all(yaml.as_bytes()).unwrap();
+        }
+
+        let mut graph = GraphHolder::new();
+
+        let filename = "simple_model.yaml";
+        let filename_cstring = CString::new(filename).unwrap();
+        let filename: *const c_char = filename_cstring.as_ptr() as *const c_char;
+        assert_eq!(
+            unsafe { forward_graph_initialize_from_yaml_file(filename, 100.0, graph.as_mut_ptr()) },
+            0
+        );
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+
+        assert_eq!(
+            unsafe { forward_graph_model_end_time(pstatus, graph.as_mut_ptr()) },
+            151.0
+        );
+
+        std::fs::remove_file("simple_model.yaml").unwrap();
+    }
+
+    #[test]
+    fn test_from_json() {
+        let json = r#"{
+  "time_units": "generations",
+  "demes": [
+    {
+      "name": "A",
+      "epochs": [
+        {"start_size": 100, "end_time": 50},
+        {"start_size": 200}
+      ]
+    }
+  ]
+}"#;
+        let mut graph = GraphHolder::new();
+        let json_cstr = CString::new(json).unwrap();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_json(json_cstr.as_ptr(), 100.0, graph.as_mut_ptr())
+            },
+            0
+        );
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert_eq!(
+            unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+            151.0
+        );
+
+        {
+            let mut file = std::fs::File::create("simple_model.json").unwrap();
+            file.write_all(json.as_bytes()).unwrap();
+        }
+        let filename = CString::new("simple_model.json").unwrap();
+        let mut graph = GraphHolder::new();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_json_file(
+                    filename.as_ptr(),
+                    100.0,
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        assert_eq!(
+            unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+            151.0
+        );
+        std::fs::remove_file("simple_model.json").unwrap();
+
+        // YAML that is not JSON
+        let yaml = CString::new("time_units: generations").unwrap();
+        assert_eq!(
+            unsafe { forward_graph_initialize_from_json(yaml.as_ptr(), 100.0, graph.as_mut_ptr()) },
+            ForwardGraphErrorCode::JsonError as i32
+        );
+        assert!(unsafe { forward_graph_is_error_state(graph.as_ptr()) });
+
+        // JSON that is not YAML
+        let json = "{\n\t\"time_units\": \"generations\",\n\t\"description\": \"a\\/b\",\n\t\"demes\": [{\"name\": \"A\", \"epochs\": [{\"start_size\": 100}]}]\n}";
+        let json = CString::new(json).unwrap();
+        assert_eq!(
+            unsafe { forward_graph_initialize_from_json(json.as_ptr(), 100.0, graph.as_mut_ptr()) },
+            0
+        );
+
+        // Errors from demes reading JSON are not YAML errors
+        let json = r#"{"time_units": "generations", "demes": [{"name": "A", "epochs": [{"start_size": 100}]}], "unknown": 1}"#;
+        let json = CString::new(json).unwrap();
+        assert_eq!(
+            unsafe { forward_graph_initialize_from_json(json.as_ptr(), 100.0, graph.as_mut_ptr()) },
+            ForwardGraphErrorCode::JsonError as i32
+        );
+        let json = r#"{"time_units": "generations", "demes": [{"name": "A", "epochs": [{"start_size": -100}]}]}"#;
+        let json = CString::new(json).unwrap();
+        assert_eq!(
+            unsafe { forward_graph_initialize_from_json(json.as_ptr(), 100.0, graph.as_mut_ptr()) },
+            ForwardGraphErrorCode::DemesError as i32
+        );
+    }
+
+    #[test]
+    fn test_from_yaml_buffer() {
+        // NOTE: no NULL byte at the end
+        let yaml = b"
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 50
+   - start_size: 200
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_buffer(
+                    yaml.as_ptr(),
+                    yaml.len(),
+                    100.0,
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert_eq!(
+            unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+            151.0
+        );
+
+        let mut invalid = yaml.to_vec();
+        invalid[5] = 0xff;
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_buffer(
+                    invalid.as_ptr(),
+                    invalid.len(),
+                    100.0,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::Utf8Error as i32
+        );
+        let message = unsafe { forward_graph_get_error_message(graph.as_ptr(), pstatus) };
+        let message = unsafe { CStr::from_ptr(message) }.to_str().unwrap();
+        assert!(message.starts_with("invalid utf8 sequence at byte offset 5"));
+        assert_eq!(
+            unsafe { forward_graph_get_error_offset(graph.as_ptr(), pstatus) },
+            5
+        );
+        assert_eq!(status, 0);
+
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_buffer(
+                    std::ptr::null(),
+                    1,
+                    100.0,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+        assert_eq!(
+            unsafe { forward_graph_get_error_offset(graph.as_ptr(), pstatus) },
+            -1
+        );
+    }
+
+    #[test]
+    fn test_time_rounding() {
+        let yaml = "
+time_units: years
+generation_time: 25
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 1260
+   - start_size: 200
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(100.0, yaml), 0);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert_eq!(
+            unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+            151.0
+        );
+
+        let options = ForwardGraphInitializationOptions {
+            burnin: 100.0,
+            rounding: ForwardGraphTimeRounding::NoRounding,
+            ..ForwardGraphInitializationOptions::default()
+        };
+        let cyaml = CString::new(yaml).unwrap();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_with_options(
+                    cyaml.as_ptr(),
+                    &options,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::TimeError as i32
+        );
+        assert!(unsafe { forward_graph_is_error_state(graph.as_ptr()) });
+
+        let yaml = "
+time_units: years
+generation_time: 25
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 1250
+   - start_size: 200
+";
+        let cyaml = CString::new(yaml).unwrap();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_with_options(
+                    cyaml.as_ptr(),
+                    &options,
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        assert_eq!(
+            unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+            151.0
+        );
+    }
+
+    #[test]
+    fn test_initialization_options() {
+        let yaml = "
+time_units: years
+generation_time: 25
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 1260
+   - start_size: 200
+";
+        let yaml = CString::new(yaml).unwrap();
+        let mut options = std::mem::MaybeUninit::<ForwardGraphInitializationOptions>::uninit();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialization_options_init(
+                    options.as_mut_ptr(),
+                    FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION,
+                )
+            },
+            0
+        );
+        let mut options = unsafe { options.assume_init() };
+        assert_eq!(
+            options.size,
+            std::mem::size_of::<ForwardGraphInitializationOptions>()
+        );
+        assert_eq!(options.burnin, 0.0);
+        options.burnin = 100.0;
+
+        let mut graph = GraphHolder::new();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_with_options(
+                    yaml.as_ptr(),
+                    &options,
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert_eq!(
+            unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+            151.0
+        );
+
+        options.rounding = ForwardGraphTimeRounding::NoRounding;
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_with_options(
+                    yaml.as_ptr(),
+                    &options,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::TimeError as i32
+        );
+
+        options.rounding = ForwardGraphTimeRounding::Round;
+        options.require_generations = true;
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_with_options(
+                    yaml.as_ptr(),
+                    &options,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::ModelError as i32
+        );
+
+        options.version = FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION + 1;
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_with_options(
+                    yaml.as_ptr(),
+                    &options,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::InvalidOptions as i32
+        );
+
+        options.version = FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION;
+        options.size -= 1;
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_with_options(
+                    yaml.as_ptr(),
+                    &options,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::InvalidOptions as i32
+        );
+
+        // Enum fields set from C may hold any integer.
+        options.size = std::mem::size_of::<ForwardGraphInitializationOptions>();
+        for field in [
+            std::mem::offset_of!(ForwardGraphInitializationOptions, rounding),
+            std::mem::offset_of!(ForwardGraphInitializationOptions, burnin_units),
+        ] {
+            let mut raw = std::mem::MaybeUninit::new(options);
+            unsafe { (raw.as_mut_ptr().cast::<u8>().add(field) as *mut i32).write(7) };
+            assert_eq!(
+                unsafe {
+                    forward_graph_initialize_from_yaml_with_options(
+                        yaml.as_ptr(),
+                        raw.as_ptr(),
+                        graph.as_mut_ptr(),
+                    )
+                },
+                ForwardGraphErrorCode::InvalidOptions as i32
+            );
+        }
+    }
+
+    #[test]
+    fn test_burnin_units() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 10
+     end_time: 50
+ - name: B
+   ancestors: [A]
+   epochs:
+   - start_size: 20
+     end_size: 40
+";
+        let yaml = CString::new(yaml).unwrap();
+        let mut options = ForwardGraphInitializationOptions::default();
+        let mut graph = GraphHolder::new();
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        for (units, burnin, end_time) in [
+            (ForwardGraphBurninUnits::Generations, 3.0, 54.0),
+            (ForwardGraphBurninUnits::RootDemeSize, 2.54, 76.0),
+            (ForwardGraphBurninUnits::MaxDemeSizeAtTimeZero, 2.54, 153.0),
+        ] {
+            options.burnin = burnin;
+            options.burnin_units = units;
+            assert_eq!(
+                unsafe {
+                    forward_graph_initialize_from_yaml_with_options(
+                        yaml.as_ptr(),
+                        &options,
+                        graph.as_mut_ptr(),
+                    )
+                },
+                0
+            );
+            assert_eq!(
+                unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
+                end_time
+            );
+        }
+    }
+
+    #[test]
+    fn test_last_error() {
+        demes_forward_clear_last_error();
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::NoError
+        );
+        assert!(demes_forward_last_error_message().is_null());
+
+        let mut graph = GraphHolder::new();
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert!(unsafe { forward_graph_selfing_rates(graph.as_ptr(), pstatus) }.is_null());
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::GraphNotInitialized
+        );
+
+        let mut options = std::mem::MaybeUninit::<ForwardGraphInitializationOptions>::uninit();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialization_options_init(
+                    options.as_mut_ptr(),
+                    FORWARD_GRAPH_INITIALIZATION_OPTIONS_VERSION + 1,
+                )
+            },
+            ForwardGraphErrorCode::InvalidOptions as i32
+        );
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::InvalidOptions
+        );
+
+        graph.init_with_yaml(100.0, "demes: [");
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::YamlError
+        );
+        let message = unsafe { forward_graph_get_error_message(graph.as_ptr(), pstatus) };
+        let last_message = demes_forward_last_error_message();
+        assert_eq!(unsafe { CStr::from_ptr(message) }, unsafe {
+            CStr::from_ptr(last_message)
+        });
+
+        // Functions that fail because the graph is
+        // in an error state also record the error.
+        for f in [
+            |graph, status| unsafe { forward_graph_model_end_time(status, graph) }.is_nan(),
+            |graph, status| {
+                unsafe { forward_graph_ancestry_proportions_matrix(graph, status) }.is_null()
+            },
+            |graph, status| {
+                unsafe { forward_graph_ancestry_proportions(0, status, graph as *mut _) }.is_null()
+            },
+        ] as [fn(*const OpaqueForwardGraph, *mut i32) -> bool; 3]
+        {
+            demes_forward_clear_last_error();
+            assert!(f(graph.as_ptr(), pstatus));
+            assert_eq!(status, ForwardGraphErrorCode::YamlError as i32);
+            assert_eq!(
+                demes_forward_last_error_code(),
+                ForwardGraphErrorCode::YamlError
+            );
+        }
+
+        // Errors on other threads are not visible here
+        std::thread::spawn(|| {
+            record_error(ForwardGraphErrorCode::InternalError);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::YamlError
+        );
+
+        demes_forward_clear_last_error();
+        assert!(demes_forward_last_error_message().is_null());
+    }
+
+    #[test]
+    fn test_null_pointers() {
+        let null_graph: *mut OpaqueForwardGraph = std::ptr::null_mut();
+        let null_status: *mut i32 = std::ptr::null_mut();
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+
+        let yaml = CString::new("demes: []").unwrap();
+        assert_eq!(
+            unsafe { forward_graph_initialize_from_yaml(yaml.as_ptr(), 0.0, null_graph) },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::NullPointer
+        );
+        assert!(unsafe { forward_graph_is_error_state(null_graph) });
+        assert_eq!(
+            unsafe { forward_graph_get_error_code(null_graph) },
+            ForwardGraphErrorCode::NullPointer
+        );
+        assert_eq!(unsafe { forward_graph_number_of_demes(null_graph) }, -1);
+        assert!(unsafe { forward_graph_parental_deme_sizes(null_graph, pstatus) }.is_null());
+        assert_eq!(status, ForwardGraphErrorCode::NullPointer as i32);
+        status = -1;
+        assert!(unsafe { forward_graph_model_end_time(pstatus, null_graph) }.is_nan());
+        assert_eq!(status, ForwardGraphErrorCode::NullPointer as i32);
+        unsafe { forward_graph_deallocate(null_graph) };
+
+        let mut graph = GraphHolder::new();
+        assert_eq!(
+            unsafe {
+                forward_graph_initialize_from_yaml_file(std::ptr::null(), 0.0, graph.as_mut_ptr())
+            },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+        assert!(
+            unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), null_status) }.is_null()
+        );
+
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+";
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        assert_eq!(
+            unsafe { forward_graph_deme_index(std::ptr::null(), pstatus, graph.as_mut_ptr()) },
+            -1
+        );
+        assert_eq!(status, ForwardGraphErrorCode::NullPointer as i32);
+        assert_eq!(
+            unsafe { forward_graph_epoch(0, 0, std::ptr::null_mut(), graph.as_mut_ptr()) },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+        assert_eq!(
+            unsafe { forward_graph_initialization_options_init(std::ptr::null_mut(), 1) },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+        assert!(!unsafe { forward_graph_is_error_state(graph.as_ptr()) });
+    }
+
+    #[test]
+    fn test_panics_are_caught() {
+        assert!(forward_graph_catch_panics());
+        let mut graph = GraphHolder::new();
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+";
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        let rv = unsafe {
+            ffi_boundary(graph.as_ptr(), pstatus, -1, || -> i32 {
+                panic!("oops");
+            })
+        };
+        assert_eq!(rv, -1);
+        assert_eq!(status, ForwardGraphErrorCode::Panic as i32);
+        assert_eq!(
+            unsafe { forward_graph_get_error_code(graph.as_ptr()) },
+            ForwardGraphErrorCode::Panic
+        );
+        let message = unsafe { forward_graph_get_error_message(graph.as_ptr(), pstatus) };
+        assert_eq!(
+            unsafe { CStr::from_ptr(message) }.to_str().unwrap(),
+            "panic: oops"
+        );
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::Panic
+        );
+        assert!(unsafe { forward_graph_parental_deme_sizes(graph.as_ptr(), pstatus) }.is_null());
+        assert_eq!(status, ForwardGraphErrorCode::GraphNotInitialized as i32);
+    }
+
+    #[cfg(feature = "checked-handles")]
+    #[test]
+    fn test_checked_handles() {
+        let graph = forward_graph_allocate();
+        let yaml = CString::new(
+            "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+",
+        )
+        .unwrap();
+        assert_eq!(
+            unsafe { forward_graph_initialize_from_yaml(yaml.as_ptr(), 10.0, graph) },
+            0
+        );
+        unsafe { forward_graph_deallocate(graph) };
+
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert!(unsafe { forward_graph_parental_deme_sizes(graph, pstatus) }.is_null());
+        assert_eq!(status, ForwardGraphErrorCode::InvalidHandle as i32);
+        assert_eq!(
+            unsafe { forward_graph_update_state(0.0, graph) },
+            ForwardGraphErrorCode::InvalidHandle as i32
+        );
+        assert_eq!(
+            unsafe { forward_graph_get_error_code(graph) },
+            ForwardGraphErrorCode::InvalidHandle
+        );
+
+        demes_forward_clear_last_error();
+        unsafe { forward_graph_deallocate(graph) };
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::InvalidHandle
+        );
+        let message = unsafe { CStr::from_ptr(demes_forward_last_error_message()) };
+        assert!(message.to_str().unwrap().ends_with("has been freed"));
+    }
+
+    #[test]
+    fn test_clone() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 50
+   - start_size: 200
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(100.0, yaml), 0);
+        assert_eq!(
+            unsafe { forward_graph_update_state(120.0, graph.as_mut_ptr()) },
+            0
+        );
+        assert_eq!(
+            unsafe { forward_graph_initialize_time_iteration(graph.as_mut_ptr()) },
+            0
+        );
+
+        let clone = unsafe { forward_graph_clone(graph.as_ptr()) };
+        assert!(!clone.is_null());
+        // The model text is shared rather than copied.
+        assert!(std::sync::Arc::ptr_eq(
+            &unsafe { &*graph.as_ptr() }.model.as_ref().unwrap().text,
+            &unsafe { &*clone }.model.as_ref().unwrap().text
+        ));
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        let sizes = unsafe { forward_graph_offspring_deme_sizes(clone, pstatus) };
+        assert_eq!(status, 0);
+        assert_eq!(unsafe { *sizes }, 200.0);
+        let time = unsafe { forward_graph_iterate_time(clone, pstatus) };
+        assert_eq!(unsafe { *time }, 120.0);
+
+        // The clone is independent of the original
+        assert_eq!(unsafe { forward_graph_update_state(0.0, clone) }, 0);
+        let sizes = unsafe { forward_graph_offspring_deme_sizes(clone, pstatus) };
+        assert_eq!(unsafe { *sizes }, 100.0);
+        let sizes = unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
+        assert_eq!(unsafe { *sizes }, 200.0);
+        unsafe { forward_graph_deallocate(clone) };
+
+        let mut uninitialized = GraphHolder::new();
+        let clone = unsafe { forward_graph_clone(uninitialized.as_ptr()) };
+        assert!(!clone.is_null());
+        assert_eq!(unsafe { forward_graph_number_of_demes(clone) }, -1);
+        unsafe { forward_graph_deallocate(clone) };
+
+        assert!(unsafe { forward_graph_clone(std::ptr::null()) }.is_null());
+    }
+
+    #[test]
+    fn test_time_iterator() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 50
+   - start_size: 200
+";
+        let mut graph = GraphHolder::new();
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        assert!(unsafe { forward_graph_time_iterator_allocate(graph.as_ptr(), pstatus) }.is_null());
+        assert_eq!(status, ForwardGraphErrorCode::GraphNotInitialized as i32);
+
+        assert_eq!(graph.init_with_yaml(100.0, yaml), 0);
+        let first = unsafe { forward_graph_time_iterator_allocate(graph.as_ptr(), pstatus) };
+        assert_eq!(status, 0);
+        assert_eq!(
+            unsafe { forward_graph_update_state(140.0, graph.as_mut_ptr()) },
+            0
+        );
+        let second = unsafe { forward_graph_time_iterator_allocate(graph.as_ptr(), pstatus) };
+
+        let mut time = f64::NAN;
+        let mut first_times = vec![];
+        let mut second_times = vec![];
+        loop {
+            let first_more = unsafe { forward_graph_time_iterator_next(first, &mut time, pstatus) };
+            assert_eq!(status, 0);
+            if first_more {
+                first_times.push(time);
+            }
+            let second_more =
+                unsafe { forward_graph_time_iterator_next(second, &mut time, pstatus) };
+            assert_eq!(status, 0);
+            if second_more {
+                second_times.push(time);
+            }
+            if !first_more && !second_more {
+                break;
+            }
+        }
+        assert_eq!(first_times, (0..151).map(f64::from).collect::<Vec<_>>());
+        assert_eq!(second_times, (140..151).map(f64::from).collect::<Vec<_>>());
+        unsafe { forward_graph_time_iterator_deallocate(first) };
+        unsafe { forward_graph_time_iterator_deallocate(second) };
+    }
+
+    #[test]
+    fn test_run() {
+        #[derive(Default)]
+        struct Visited {
+            times: Vec<f64>,
+            parental_sizes: Vec<f64>,
+            last_offspring_null: bool,
+            stop_at: Option<f64>,
+        }
+
+        unsafe extern "C" fn callback(
+            time: f64,
+            num_demes: usize,
+            parental_deme_sizes: *const f64,
+            offspring_deme_sizes: *const f64,
+            ancestry_proportions: *const f64,
+            user_data: *mut c_void,
+        ) -> i32 {
+            let visited = &mut *(user_data as *mut Visited);
+            assert_eq!(num_demes, 1);
+            visited.times.push(time);
+            visited.parental_sizes.push(*parental_deme_sizes);
+            visited.last_offspring_null = offspring_deme_sizes.is_null();
+            assert_eq!(
+                offspring_deme_sizes.is_null(),
+                ancestry_proportions.is_null()
+            );
+            match visited.stop_at {
+                Some(t) if t == time => 1,
+                _ => 0,
+            }
+        }
+
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 50
+   - start_size: 200
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(100.0, yaml), 0);
+
+        let mut visited = Visited::default();
+        assert_eq!(
+            unsafe {
+                forward_graph_run(
+                    Some(callback),
+                    &mut visited as *mut Visited as *mut c_void,
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        assert_eq!(visited.times, (0..151).map(f64::from).collect::<Vec<_>>());
+        assert_eq!(visited.parental_sizes[100], 100.0);
+        assert_eq!(visited.parental_sizes[101], 200.0);
+        assert!(visited.last_offspring_null);
+
+        let mut visited = Visited {
+            stop_at: Some(10.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            unsafe {
+                forward_graph_run(
+                    Some(callback),
+                    &mut visited as *mut Visited as *mut c_void,
+                    graph.as_mut_ptr(),
+                )
+            },
+            1
+        );
+        assert_eq!(visited.times.len(), 11);
+
+        assert_eq!(
+            unsafe { forward_graph_run(None, std::ptr::null_mut(), graph.as_mut_ptr()) },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+    }
+
+    #[test]
+    fn test_fill_trajectory() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+     end_time: 5
+   - start_size: 200
+     selfing_rate: 0.5
+ - name: B
+   ancestors: [A]
+   start_time: 5
+   epochs:
+   - start_size: 50
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(5.0, yaml), 0);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        let num_generations = unsafe { forward_graph_trajectory_length(graph.as_ptr(), pstatus) };
+        assert_eq!(status, 0);
+        assert_eq!(num_generations, 11);
+        let num_demes = 2;
+
+        let mut parental = vec![f64::NAN; num_generations * num_demes];
+        let mut offspring = vec![f64::NAN; num_generations * num_demes];
+        let mut selfing = vec![f64::NAN; num_generations * num_demes];
+        let mut ancestry = vec![f64::NAN; num_generations * num_demes * num_demes];
+        assert_eq!(
+            unsafe {
+                forward_graph_fill_trajectory(
+                    parental.as_mut_ptr(),
+                    offspring.as_mut_ptr(),
+                    selfing.as_mut_ptr(),
+                    std::ptr::null_mut(),
+                    ancestry.as_mut_ptr(),
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+
+        for time in 0..num_generations {
+            assert_eq!(
+                unsafe { forward_graph_update_state(time as f64, graph.as_mut_ptr()) },
+                0
+            );
+            let expected = unsafe { forward_graph_parental_deme_sizes(graph.as_ptr(), pstatus) };
+            let expected = unsafe { std::slice::from_raw_parts(expected, num_demes) };
+            assert_eq!(
+                &parental[time * num_demes..(time + 1) * num_demes],
+                expected
+            );
+            let expected =
+                unsafe { forward_graph_ancestry_proportions_matrix(graph.as_ptr(), pstatus) };
+            let row = &ancestry[time * num_demes * num_demes..(time + 1) * num_demes * num_demes];
+            if expected.is_null() {
+                assert!(row.iter().all(|&x| x == 0.0));
+                assert!(offspring[time * num_demes..(time + 1) * num_demes]
+                    .iter()
+                    .all(|&x| x == 0.0));
+            } else {
+                let expected =
+                    unsafe { std::slice::from_raw_parts(expected, num_demes * num_demes) };
+                assert_eq!(row, expected);
+                let expected = unsafe { forward_graph_selfing_rates(graph.as_ptr(), pstatus) };
+                let expected = unsafe { std::slice::from_raw_parts(expected, num_demes) };
+                assert_eq!(&selfing[time * num_demes..(time + 1) * num_demes], expected);
+            }
+        }
+        assert_eq!(&offspring[4 * num_demes..5 * num_demes], &[100.0, 0.0]);
+        assert_eq!(&offspring[5 * num_demes..6 * num_demes], &[200.0, 50.0]);
+        assert_eq!(&offspring[10 * num_demes..], &[0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_integer_deme_sizes() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100.5
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        assert_eq!(
+            unsafe { forward_graph_update_state(0.0, graph.as_mut_ptr()) },
+            0
+        );
+        let size = 100.5_f64;
+
+        let mut size_u32 = 0_u32;
+        let mut size_u64 = 0_u64;
+        for (rounding, expected) in [
+            (ForwardGraphSizeRounding::HalfEven, 100.0),
+            (ForwardGraphSizeRounding::Floor, 100.0),
+            (ForwardGraphSizeRounding::Ceil, 101.0),
+        ] {
+            assert_eq!(
+                unsafe {
+                    forward_graph_offspring_deme_sizes_u32(
+                        rounding as i32,
+                        std::ptr::null(),
+                        &mut size_u32,
+                        graph.as_mut_ptr(),
+                    )
+                },
+                0
+            );
+            assert_eq!(size_u32 as f64, expected);
+            assert_eq!(
+                unsafe {
+                    forward_graph_offspring_deme_sizes_u64(
+                        rounding as i32,
+                        std::ptr::null(),
+                        &mut size_u64,
+                        graph.as_mut_ptr(),
+                    )
+                },
+                0
+            );
+            assert_eq!(size_u64 as f64, expected);
+        }
+
+        let mut total = 0.0;
+        let replicates = 10000;
+        let default_rng = forward_graph_default_rng_allocate(42);
+        let rng = unsafe { forward_graph_default_rng(default_rng) };
+        for _ in 0..replicates {
+            assert_eq!(
+                unsafe {
+                    forward_graph_parental_deme_sizes_u64(
+                        ForwardGraphSizeRounding::Stochastic as i32,
+                        &rng,
+                        &mut size_u64,
+                        graph.as_mut_ptr(),
+                    )
+                },
+                0
+            );
+            total += size_u64 as f64;
+        }
+        assert!((total / replicates as f64 - size).abs() < 0.05);
+        unsafe { forward_graph_default_rng_deallocate(default_rng) };
+
+        assert_eq!(
+            unsafe {
+                forward_graph_parental_deme_sizes_u64(
+                    ForwardGraphSizeRounding::Stochastic as i32,
+                    std::ptr::null(),
+                    &mut size_u64,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+
+        // A size that only fits in a u64.
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 5e9
+";
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        assert_eq!(
+            unsafe { forward_graph_update_state(0.0, graph.as_mut_ptr()) },
+            0
+        );
+        assert_eq!(
+            unsafe {
+                forward_graph_parental_deme_sizes_u32(
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    std::ptr::null(),
+                    &mut size_u32,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::IntegerOverflow as i32
+        );
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::IntegerOverflow
+        );
+        assert!(!unsafe { forward_graph_is_error_state(graph.as_ptr()) });
+        assert_eq!(
+            unsafe {
+                forward_graph_parental_deme_sizes_u64(
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    std::ptr::null(),
+                    &mut size_u64,
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        assert_eq!(size_u64, 5_000_000_000);
+
+        assert_eq!(
+            unsafe {
+                forward_graph_parental_deme_sizes_u64(
+                    7,
+                    std::ptr::null(),
+                    &mut size_u64,
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::InvalidArgument as i32
+        );
+    }
+
+    #[test]
+    fn test_alias_table() {
+        let weights = [0.1, 0.0, 0.6, 0.3];
+        let table = AliasTable::new(&weights).unwrap();
+        let n = 100000;
+        let mut counts = [0_usize; 4];
+        for i in 0..n {
+            counts[table.sample((i as f64 + 0.5) / n as f64)] += 1;
+        }
+        for (count, weight) in counts.iter().zip(weights) {
+            assert!((*count as f64 / n as f64 - weight).abs() < 1e-3);
+        }
+        assert!(AliasTable::new(&[0.0, 0.0]).is_none());
+    }
+
+    #[test]
+    fn test_parent_deme_sampler() {
+        let yaml = "
+time_units: generations
+demes:
+ - name: A
+   epochs:
+   - start_size: 100
+ - name: B
+   epochs:
+   - start_size: 100
+ - name: C
+   ancestors: [A, B]
+   proportions: [0.25, 0.75]
+   start_time: 10
+   epochs:
+   - start_size: 100
+";
+        let mut graph = GraphHolder::new();
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        let mut status = -1;
+        let pstatus: *mut i32 = &mut status;
+        // The generation in which C is founded
+        assert_eq!(
+            unsafe { forward_graph_update_state(10.0, graph.as_mut_ptr()) },
+            0
+        );
+        let sampler =
+            unsafe { forward_graph_parent_deme_sampler_allocate(graph.as_ptr(), pstatus) };
+        assert_eq!(status, 0);
+        assert!(!sampler.is_null());
+        for (uniform, expected) in [(0.0, 0), (0.2, 0), (0.3, 1), (0.99, 1)] {
+            assert_eq!(
+                unsafe { forward_graph_parent_deme_sampler_sample(sampler, 2, uniform, pstatus) },
+                expected
+            );
+            assert_eq!(status, 0);
+        }
+        assert_eq!(
+            unsafe { forward_graph_parent_deme_sampler_sample(sampler, 0, 0.5, pstatus) },
+            0
+        );
+        assert_eq!(
+            unsafe { forward_graph_parent_deme_sampler_sample(sampler, 2, 1.0, pstatus) },
+            -1
+        );
+        assert_eq!(status, ForwardGraphErrorCode::InvalidArgument as i32);
+        assert_eq!(
+            unsafe { forward_graph_parent_deme_sampler_sample(sampler, 3, 0.5, pstatus) },
+            -1
+        );
+        assert_eq!(status, ForwardGraphErrorCode::IndexOutOfRange as i32);
+
+        let default_rng = forward_graph_default_rng_allocate(0);
+        let rng = unsafe { forward_graph_default_rng(default_rng) };
+        let mut counts = [0; 3];
+        for _ in 0..1000 {
+            let deme =
+                unsafe { forward_graph_parent_deme_sampler_sample_rng(sampler, 2, &rng, pstatus) };
+            assert_eq!(status, 0);
+            counts[deme as usize] += 1;
+        }
+        assert!(counts[0] > 0 && counts[1] > counts[0] && counts[2] == 0);
+        unsafe { forward_graph_default_rng_deallocate(default_rng) };
+
+        // Before C exists
+        assert_eq!(
+            unsafe { forward_graph_update_state(0.0, graph.as_mut_ptr()) },
+            0
+        );
+        assert_eq!(
+            unsafe { forward_graph_parent_deme_sampler_update(sampler, graph.as_ptr()) },
+            0
+        );
+        assert_eq!(
+            unsafe { forward_graph_parent_deme_sampler_sample(sampler, 2, 0.5, pstatus) },
+            -1
+        );
+        assert_eq!(status, ForwardGraphErrorCode::NoAncestry as i32);
+        unsafe { forward_graph_parent_deme_sampler_deallocate(sampler) };
+    }
+
+    #[test]
+    fn test_rng() {
+        let first = forward_graph_default_rng_allocate(1);
+        let second = forward_graph_default_rng_allocate(1);
+        for _ in 0..100 {
+            let x = unsafe { forward_graph_default_rng_uniform(first as *mut c_void) };
+            assert!((0.0..1.0).contains(&x));
+            assert_eq!(x, unsafe {
+                forward_graph_default_rng_uniform(second as *mut c_void)
+            });
         }
+        unsafe { forward_graph_default_rng_deallocate(first) };
+        unsafe { forward_graph_default_rng_deallocate(second) };
 
-        fn init_with_yaml(&mut self, burnin: f64, yaml: &str) -> i32 {
-            let yaml_cstr = CString::new(yaml).unwrap();
-            let yaml_c_char: *const c_char = yaml_cstr.as_ptr() as *const c_char;
-            unsafe { forward_graph_initialize_from_yaml(yaml_c_char, burnin, self.as_mut_ptr()) }
+        unsafe extern "C" fn bad_uniform(_: *mut c_void) -> f64 {
+            1.0
         }
+        let rng = ForwardGraphRng {
+            uniform: Some(bad_uniform),
+            state: std::ptr::null_mut(),
+        };
+        assert_eq!(
+            unsafe { rng.uniform() }.unwrap_err().0,
+            ForwardGraphErrorCode::InvalidArgument
+        );
     }
 
-    impl Drop for GraphHolder {
-        fn drop(&mut self) {
-            unsafe { forward_graph_deallocate(self.as_mut_ptr()) };
+    #[test]
+    fn test_largest_remainder() {
+        assert_eq!(largest_remainder(10, &[0.25, 0.75]), vec![3, 7]);
+        assert_eq!(largest_remainder(3, &[0.5, 0.0, 0.5]), vec![2, 0, 1]);
+        assert_eq!(largest_remainder(7, &[1.0 / 3.0; 3]), vec![3, 2, 2]);
+        assert_eq!(largest_remainder(0, &[0.5, 0.5]), vec![0, 0]);
+        // Large sizes are rounded up when converted to f64.
+        let n = (1 << 53) + 3;
+        assert_eq!(largest_remainder(n, &[1.0]), vec![n]);
+        assert_eq!(
+            largest_remainder(n, &[0.5, 0.5]),
+            vec![(1 << 52) + 2, (1 << 52) + 1]
+        );
+    }
+
+    #[test]
+    fn test_binomial() {
+        let exact = (2..=30).map(|i| (i as f64).ln()).sum::<f64>();
+        assert!((ln_factorial(30) - exact).abs() < 1e-10);
+
+        assert_eq!(binomial(0, 0.5, 0.5), 0);
+        assert_eq!(binomial(10, 0.0, 0.5), 0);
+        assert_eq!(binomial(10, 1.0, 0.5), 10);
+
+        let mut rng = SplitMix64(5);
+        for (n, p) in [(20, 0.1), (1000, 0.5), (10_000_000, 0.25)] {
+            let replicates = 10000;
+            let draws = (0..replicates)
+                .map(|_| binomial(n, p, rng.uniform()) as f64)
+                .collect::<Vec<_>>();
+            let mean = draws.iter().sum::<f64>() / replicates as f64;
+            let variance =
+                draws.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / replicates as f64;
+            let expected_variance = n as f64 * p * (1.0 - p);
+            assert!((mean - n as f64 * p).abs() < 4.0 * (expected_variance / 1e4).sqrt());
+            assert!((variance / expected_variance - 1.0).abs() < 0.1);
         }
     }
 
     #[test]
-    fn test_invalid_graph() {
+    fn test_offspring_allocation() {
         let yaml = "
 time_units: generations
 demes:
  - name: A
-   start_time: 55
    epochs:
    - start_size: 100
-     end_time: 50
-   - start_size: 200
+ - name: B
+   epochs:
+   - start_size: 100
+ - name: C
+   ancestors: [A, B]
+   proportions: [0.25, 0.75]
+   start_time: 10
+   epochs:
+   - start_size: 101
 ";
         let mut graph = GraphHolder::new();
-        graph.init_with_yaml(100.0, yaml);
-        assert!(unsafe { forward_graph_is_error_state(graph.as_ptr()) });
-        let mut status = -1;
-        let pstatus: *mut i32 = &mut status;
-        let message = unsafe { forward_graph_get_error_message(graph.as_ptr(), pstatus) };
-        assert_eq!(status, 0);
-        assert!(!message.is_null());
-        let rust_message = unsafe { CStr::from_ptr(message) };
-        let rust_message: &str = rust_message.to_str().unwrap();
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
+        let mut counts = vec![u64::MAX; 9];
         assert_eq!(
-            rust_message,
-            "deme A has finite start time but no ancestors"
+            unsafe { forward_graph_update_state(0.0, graph.as_mut_ptr()) },
+            0
         );
-    }
+        assert_eq!(
+            unsafe {
+                forward_graph_offspring_allocation(
+                    ForwardGraphAllocationMethod::LargestRemainder as i32,
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    std::ptr::null(),
+                    counts.as_mut_ptr(),
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        assert_eq!(counts, vec![100, 0, 0, 0, 100, 0, 0, 0, 0]);
 
-    #[test]
-    fn test_empty_graph() {
-        let yaml = "";
-        let mut graph = GraphHolder::new();
-        graph.init_with_yaml(100.0, yaml);
-        assert!(unsafe { forward_graph_is_error_state(graph.as_ptr()) });
-    }
+        // The generation in which C is founded
+        assert_eq!(
+            unsafe { forward_graph_update_state(10.0, graph.as_mut_ptr()) },
+            0
+        );
+        assert_eq!(
+            unsafe {
+                forward_graph_offspring_allocation(
+                    ForwardGraphAllocationMethod::LargestRemainder as i32,
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    std::ptr::null(),
+                    counts.as_mut_ptr(),
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        assert_eq!(&counts[6..], &[25, 76, 0]);
 
-    #[test]
-    fn test_null_graph() {
-        let yaml: *const c_char = std::ptr::null();
-        let graph = forward_graph_allocate();
-        unsafe { forward_graph_initialize_from_yaml(yaml, 100.0, graph) };
-        assert!(unsafe { forward_graph_is_error_state(graph) });
-        unsafe { forward_graph_deallocate(graph) };
-    }
+        let default_rng = forward_graph_default_rng_allocate(3);
+        let rng = unsafe { forward_graph_default_rng(default_rng) };
+        assert_eq!(
+            unsafe {
+                forward_graph_offspring_allocation(
+                    ForwardGraphAllocationMethod::Multinomial as i32,
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    &rng,
+                    counts.as_mut_ptr(),
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        assert_eq!(&counts[..6], &[100, 0, 0, 0, 100, 0]);
+        assert_eq!(counts[6] + counts[7], 101);
+        assert_eq!(counts[8], 0);
+        unsafe { forward_graph_default_rng_deallocate(default_rng) };
 
-    #[test]
-    fn number_of_demes_in_model() {
-        {
-            let yaml = "
-time_units: generations
-demes:
- - name: A
-   epochs:
-   - start_size: 100
-     end_time: 50
-   - start_size: 200
-";
-            let mut graph = GraphHolder::new();
-            graph.init_with_yaml(100.0, yaml);
-            let num_demes = unsafe { forward_graph_number_of_demes(graph.as_ptr()) };
-            assert_eq!(num_demes, 1);
+        assert_eq!(
+            unsafe {
+                forward_graph_offspring_allocation(
+                    ForwardGraphAllocationMethod::Multinomial as i32,
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    std::ptr::null(),
+                    counts.as_mut_ptr(),
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
+        // The counts are not written if the generator fails,
+        // including the rows that do not need random numbers.
+        unsafe extern "C" fn bad_uniform(_: *mut c_void) -> f64 {
+            1.0
         }
+        let bad_rng = ForwardGraphRng {
+            uniform: Some(bad_uniform),
+            state: std::ptr::null_mut(),
+        };
+        counts.fill(u64::MAX);
+        assert_eq!(
+            unsafe {
+                forward_graph_offspring_allocation(
+                    ForwardGraphAllocationMethod::Multinomial as i32,
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    &bad_rng,
+                    counts.as_mut_ptr(),
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::InvalidArgument as i32
+        );
+        assert!(counts.iter().all(|&count| count == u64::MAX));
+
+        assert_eq!(
+            unsafe {
+                forward_graph_offspring_allocation(
+                    2,
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    std::ptr::null(),
+                    counts.as_mut_ptr(),
+                    graph.as_mut_ptr(),
+                )
+            },
+            ForwardGraphErrorCode::InvalidArgument as i32
+        );
     }
 
     #[test]
-    fn iterate_simple_model() {
+    fn test_parent_picker() {
         let yaml = "
 time_units: generations
 demes:
  - name: A
    epochs:
    - start_size: 100
-     end_time: 50
-   - start_size: 200
+     cloning_rate: 0.2
+     selfing_rate: 0.3
+ - name: B
+   epochs:
+   - start_size: 50
+ - name: C
+   ancestors: [A, B]
+   proportions: [0.25, 0.75]
+   start_time: 10
+   epochs:
+   - start_size: 100
+ - name: D
+   epochs:
+   - start_size: 1
 ";
         let mut graph = GraphHolder::new();
-        graph.init_with_yaml(100.0, yaml);
+        assert_eq!(graph.init_with_yaml(10.0, yaml), 0);
         let mut status = -1;
         let pstatus: *mut i32 = &mut status;
-        assert!(unsafe { forward_graph_selfing_rates(graph.as_ptr(), pstatus) }.is_null());
-        assert_eq!(status, 0);
-        status = -1;
-        assert!(unsafe { forward_graph_cloning_rates(graph.as_ptr(), pstatus) }.is_null());
-        assert_eq!(status, 0);
-        status = -1;
-        assert!(unsafe { forward_graph_parental_deme_sizes(graph.as_ptr(), pstatus) }.is_null(),);
-        assert_eq!(status, 0);
-        status = -1;
-        assert!(unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) }.is_null(),);
-        assert_eq!(status, 0);
-        status = -1;
-        assert!(!unsafe { forward_graph_any_extant_offspring_demes(graph.as_ptr(), pstatus) });
-        assert_eq!(status, 0);
-        status = -1;
-        assert!(!unsafe { forward_graph_any_extant_parent_demes(graph.as_ptr(), pstatus) });
+        // The generation in which C is founded
+        assert_eq!(
+            unsafe { forward_graph_update_state(10.0, graph.as_mut_ptr()) },
+            0
+        );
+        let picker = unsafe {
+            forward_graph_parent_picker_allocate(
+                ForwardGraphSizeRounding::HalfEven as i32,
+                std::ptr::null(),
+                graph.as_mut_ptr(),
+                pstatus,
+            )
+        };
         assert_eq!(status, 0);
+        assert!(!picker.is_null());
 
-        {
-            assert_eq!(
-                unsafe { forward_graph_initialize_time_iteration(graph.as_mut_ptr()) },
-                0,
-            );
-            let mut ngens = -1_i32;
-            let mut ptime: *const f64;
-            let mut ancestry_proportions: *const f64;
-            let mut times = vec![];
-            let mut sizes = vec![100.0; 100];
-            sizes.append(&mut vec![200.0; 50]);
-
-            let mut status = -1;
-            let pstatus: *mut i32 = &mut status;
-            ptime = unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) };
+        let default_rng = forward_graph_default_rng_allocate(1);
+        let rng = unsafe { forward_graph_default_rng(default_rng) };
+        let mut parents = ForwardGraphParents {
+            first: ForwardGraphParent { deme: 0, index: 0 },
+            second: ForwardGraphParent { deme: 0, index: 0 },
+            mating: ForwardGraphMating::Outcrossing,
+        };
+        let mut matings = [0; 3];
+        for _ in 0..1000 {
             assert_eq!(
-                unsafe { forward_graph_model_end_time(pstatus, graph.as_ptr()) },
-                151.0
+                unsafe { forward_graph_parent_picker_pick(picker, 0, &rng, &mut parents) },
+                0
             );
-            assert_eq!(status, 0);
-            while !ptime.is_null() {
-                assert_eq!(status, 0);
-                ngens += 1;
-                unsafe { times.push(*ptime) };
-                assert_eq!(
-                    unsafe { forward_graph_update_state(*ptime, graph.as_mut_ptr()) },
-                    0,
-                );
-                let mut status = -1;
-                let pstatus: *mut i32 = &mut status;
-                if unsafe { forward_graph_any_extant_offspring_demes(graph.as_ptr(), pstatus) } {
-                    assert_eq!(status, 0);
-                    let offspring_deme_sizes =
-                        unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
-                    assert_eq!(status, 0);
-                    assert!(!offspring_deme_sizes.is_null());
-                    ancestry_proportions = unsafe {
-                        forward_graph_ancestry_proportions(0, pstatus, graph.as_mut_ptr())
-                    };
-                    assert_eq!(status, 0);
-                    let ancestry_proportions =
-                        unsafe { std::slice::from_raw_parts(ancestry_proportions, 1) };
-                    assert!((ancestry_proportions[0] - 1.0) <= 1e-9);
-                    let deme_sizes = unsafe { std::slice::from_raw_parts(offspring_deme_sizes, 1) };
-                    assert_eq!(deme_sizes[0], sizes[ngens as usize]);
-                } else {
-                    status = -1;
-                    let offspring_deme_sizes =
-                        unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
-                    assert_eq!(status, 0);
-                    assert!(offspring_deme_sizes.is_null());
-                }
-                ptime = unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) };
+            assert_eq!(parents.first.deme, 0);
+            assert!(parents.first.index < 100);
+            assert_eq!(parents.second.deme, 0);
+            assert!(parents.second.index < 100);
+            match parents.mating {
+                ForwardGraphMating::Outcrossing => assert_ne!(parents.first, parents.second),
+                _ => assert_eq!(parents.first, parents.second),
             }
-            assert!(ptime.is_null());
-            assert_eq!(times.first().unwrap(), &0.0);
-            assert_eq!(times.last().unwrap(), &150.0);
-            assert_eq!(ngens, 150);
+            matings[parents.mating as usize] += 1;
         }
+        assert!((400..600).contains(&matings[0]));
+        assert!((200..400).contains(&matings[1]));
+        assert!((100..300).contains(&matings[2]));
 
-        // Now, start from time of 50
-        {
-            assert_eq!(
-                unsafe { forward_graph_update_state(50.0, graph.as_mut_ptr()) },
-                0,
-            );
+        let mut demes = [0; 4];
+        for _ in 0..1000 {
             assert_eq!(
-                unsafe { forward_graph_initialize_time_iteration(graph.as_mut_ptr()) },
-                0,
+                unsafe { forward_graph_parent_picker_pick(picker, 2, &rng, &mut parents) },
+                0
             );
-            let mut ngens = -1_i32;
-            let mut ptime: *const f64;
-            let mut times = vec![];
-            let mut sizes = vec![100.0; 50];
-            sizes.append(&mut vec![200.0; 50]);
-
-            let mut status = -1;
-            let pstatus: *mut i32 = &mut status;
-            ptime = unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) };
-            while !ptime.is_null() {
-                assert_eq!(status, 0);
-                ngens += 1;
-                unsafe { times.push(*ptime) };
-                assert_eq!(
-                    unsafe { forward_graph_update_state(*ptime, graph.as_mut_ptr()) },
-                    0,
-                );
-                let mut status = -1;
-                let pstatus: *mut i32 = &mut status;
-                if unsafe { forward_graph_any_extant_offspring_demes(graph.as_ptr(), pstatus) } {
-                    assert_eq!(status, 0);
-                    let offspring_deme_sizes =
-                        unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
-                    assert_eq!(status, 0);
-                    assert!(!offspring_deme_sizes.is_null());
-                    let deme_sizes = unsafe { std::slice::from_raw_parts(offspring_deme_sizes, 1) };
-                    assert_eq!(deme_sizes[0], sizes[ngens as usize]);
-                } else {
-                    status = -1;
-                    let offspring_deme_sizes =
-                        unsafe { forward_graph_offspring_deme_sizes(graph.as_ptr(), pstatus) };
-                    assert_eq!(status, 0);
-                    assert!(offspring_deme_sizes.is_null());
-                }
-                ptime = unsafe { forward_graph_iterate_time(graph.as_mut_ptr(), pstatus) };
-            }
-            assert!(ptime.is_null());
-            assert_eq!(times.first().unwrap(), &50.0);
-            assert_eq!(times.last().unwrap(), &150.0);
-            assert_eq!(ngens, 100);
+            assert_eq!(parents.mating, ForwardGraphMating::Outcrossing);
+            assert!(parents.first.index < if parents.first.deme == 0 { 100 } else { 50 });
+            demes[parents.first.deme] += 1;
+            demes[parents.second.deme] += 1;
         }
-    }
+        assert_eq!(demes[0] + demes[1], 2000);
+        assert!((400..600).contains(&demes[0]));
 
-    #[test]
-    fn test_from_yaml_file() {
-        let yaml = "
-time_units: generations
-demes:
- - name: A
-   epochs:
-   - start_size: 100
-     end_time: 50
-   - start_size: 200
-";
-        {
-            let mut file = std::fs::File::create("simple_model.yaml").unwrap();
-            file.write_all(yaml.as_bytes()).unwrap();
+        // A generator that always returns the same number
+        // still gives two different parents.
+        unsafe extern "C" fn constant_uniform(_: *mut c_void) -> f64 {
+            0.5
+        }
+        let constant_rng = ForwardGraphRng {
+            uniform: Some(constant_uniform),
+            state: std::ptr::null_mut(),
+        };
+        for offspring_deme in [0, 2] {
+            assert_eq!(
+                unsafe {
+                    forward_graph_parent_picker_pick(
+                        picker,
+                        offspring_deme,
+                        &constant_rng,
+                        &mut parents,
+                    )
+                },
+                0
+            );
+            assert_eq!(parents.mating, ForwardGraphMating::Outcrossing);
+            assert_ne!(parents.first, parents.second);
         }
 
-        let mut graph = GraphHolder::new();
+        // A single individual cannot outcross with itself.
+        assert_eq!(
+            unsafe { forward_graph_parent_picker_pick(picker, 3, &rng, &mut parents) },
+            ForwardGraphErrorCode::InvalidArgument as i32
+        );
+        assert_eq!(
+            demes_forward_last_error_code(),
+            ForwardGraphErrorCode::InvalidArgument
+        );
+        assert_eq!(
+            unsafe { forward_graph_parent_picker_pick(picker, 4, &rng, &mut parents) },
+            ForwardGraphErrorCode::IndexOutOfRange as i32
+        );
+        assert_eq!(
+            unsafe { forward_graph_parent_picker_pick(picker, 0, std::ptr::null(), &mut parents) },
+            ForwardGraphErrorCode::NullPointer as i32
+        );
 
-        let filename = "simple_model.yaml";
-        let filename_cstring = CString::new(filename).unwrap();
-        let filename: *const c_char = filename_cstring.as_ptr() as *const c_char;
+        // Before C is founded
         assert_eq!(
-            unsafe { forward_graph_initialize_from_yaml_file(filename, 100.0, graph.as_mut_ptr()) },
+            unsafe { forward_graph_update_state(0.0, graph.as_mut_ptr()) },
             0
         );
-        let mut status = -1;
-        let pstatus: *mut i32 = &mut status;
-
         assert_eq!(
-            unsafe { forward_graph_model_end_time(pstatus, graph.as_mut_ptr()) },
-            151.0
+            unsafe {
+                forward_graph_parent_picker_update(
+                    picker,
+                    ForwardGraphSizeRounding::HalfEven as i32,
+                    std::ptr::null(),
+                    graph.as_mut_ptr(),
+                )
+            },
+            0
+        );
+        assert_eq!(
+            unsafe { forward_graph_parent_picker_pick(picker, 2, &rng, &mut parents) },
+            ForwardGraphErrorCode::NoAncestry as i32
         );
 
-        std::fs::remove_file("simple_model.yaml").unwrap();
+        unsafe { forward_graph_parent_picker_deallocate(picker) };
+        unsafe { forward_graph_default_rng_deallocate(default_rng) };
     }
 }